@@ -0,0 +1,316 @@
+use crate::spotify::{IdType, SpotifyResource};
+use crate::tidal::{self, FullTidalResource, TidalResource};
+use isopod::models::Video;
+use poise::CreateReply;
+use prawn::models::IncludedInner;
+use serenity::all::{
+    CreateActionRow, CreateAllowedMentions, CreateButton, CreateEmbed, CreateEmbedFooter,
+    CreateMessage, Message,
+};
+use url::Url;
+
+// Discord allows at most this many embeds, and five buttons in a row, per message.
+const MAX_EMBEDS: usize = 10;
+const MAX_BUTTONS: usize = 5;
+
+/// What is shown in chat about a posted album or track, with links to it on every
+/// service it could be found on.
+#[derive(Clone, Debug)]
+pub struct Card {
+    // The service the posted link was for.
+    pub service: &'static str,
+    // "Album", "Track" or "Video".
+    pub kind: &'static str,
+    pub title: String,
+    pub artists: Vec<String>,
+    // The album itself, or the album a track is on.
+    pub album: Option<String>,
+    pub genres: Vec<String>,
+    pub release_year: Option<String>,
+    pub track_count: Option<u32>,
+    pub cover_url: Option<String>,
+    // Service display name and url, in the order they are shown.
+    pub links: Vec<(&'static str, String)>,
+}
+
+impl Card {
+    /// Builds a card from a Spotify album or track. `genres` are the primary artist's,
+    /// since Spotify rarely gives albums any of their own.
+    pub fn from_spotify(
+        resource: &SpotifyResource,
+        tidal: Option<&TidalResource>,
+        genres: Vec<String>,
+    ) -> Self {
+        let mut card = match resource {
+            SpotifyResource::Album(album) => Self {
+                service: "spotify",
+                kind: "Album",
+                title: album.name.clone(),
+                artists: album.artists.iter().map(|a| a.name.clone()).collect(),
+                album: Some(album.name.clone()),
+                genres: if genres.is_empty() {
+                    album.genres.clone()
+                } else {
+                    genres
+                },
+                release_year: year(album.release_date.as_str()),
+                track_count: Some(album.tracks.total),
+                cover_url: album.images.first().map(|i| i.url.clone()),
+                links: album
+                    .external_urls
+                    .get("spotify")
+                    .map(|u| vec![("Spotify", u.clone())])
+                    .unwrap_or_default(),
+            },
+            SpotifyResource::Track(track) => Self {
+                service: "spotify",
+                kind: "Track",
+                title: track.name.clone(),
+                artists: track.artists.iter().map(|a| a.name.clone()).collect(),
+                album: Some(track.album.name.clone()),
+                genres,
+                release_year: track.album.release_date.as_deref().and_then(year),
+                track_count: None,
+                cover_url: track.album.images.first().map(|i| i.url.clone()),
+                links: track
+                    .external_urls
+                    .get("spotify")
+                    .map(|u| vec![("Spotify", u.clone())])
+                    .unwrap_or_default(),
+            },
+        };
+
+        if let Some(t) = tidal {
+            card.links.push(("TIDAL", tidal_url(t)));
+        }
+
+        card.add_youtube_music_search();
+
+        card
+    }
+
+    /// Builds a card from a Tidal album or track, or `None` if it came back without its
+    /// details.
+    pub fn from_tidal(
+        resource: &FullTidalResource,
+        cover_url: Option<String>,
+        spotify: Option<&IdType>,
+    ) -> Option<Self> {
+        let mut card = match resource {
+            FullTidalResource::Album(album) => {
+                let attrs = album.data.attributes.as_ref()?;
+
+                Self {
+                    service: "tidal",
+                    kind: "Album",
+                    title: attrs.title.clone(),
+                    artists: tidal::artist_names(album.included.as_ref()),
+                    album: Some(attrs.title.clone()),
+                    genres: vec![],
+                    release_year: attrs.release_date.as_deref().and_then(year),
+                    track_count: u32::try_from(attrs.number_of_items).ok(),
+                    cover_url,
+                    links: vec![],
+                }
+            }
+            FullTidalResource::Track(track) => {
+                let attrs = track.data.attributes.as_ref()?;
+
+                let on_album = track.included.iter().flatten().find_map(|i| match i {
+                    IncludedInner::Albums(a) => a.attributes.as_deref(),
+                    _ => None,
+                });
+
+                Self {
+                    service: "tidal",
+                    kind: "Track",
+                    title: attrs.title.clone(),
+                    artists: tidal::artist_names(track.included.as_ref()),
+                    album: on_album.map(|a| a.title.clone()),
+                    genres: vec![],
+                    release_year: on_album
+                        .and_then(|a| a.release_date.as_deref())
+                        .and_then(year),
+                    track_count: None,
+                    cover_url,
+                    links: vec![],
+                }
+            }
+        };
+
+        card.links.push(("TIDAL", tidal_url(&resource.resource())));
+
+        if let Some(s) = spotify {
+            card.links.push(("Spotify", spotify_url(s)));
+        }
+
+        card.add_youtube_music_search();
+
+        Some(card)
+    }
+
+    /// Builds a card from a YouTube video's details, or `None` if it came back without
+    /// them.
+    pub fn from_youtube(video: &Video) -> Option<Self> {
+        let id = video.id.as_deref()?;
+        let snippet = video.snippet.as_ref()?;
+
+        // Videos from a YouTube Music artist are posted on an auto-generated
+        // "<artist> - Topic" channel.
+        let artists = snippet
+            .channel_title
+            .as_deref()
+            .map(|c| vec![c.trim_end_matches(" - Topic").to_string()])
+            .unwrap_or_default();
+
+        let thumbnails = snippet.thumbnails.as_ref();
+        let cover_url = thumbnails
+            .and_then(|t| t.high.as_ref().or(t.medium.as_ref()).or(t.default.as_ref()))
+            .and_then(|t| t.url.clone())
+            .unwrap_or_else(|| format!("https://i.ytimg.com/vi/{id}/hqdefault.jpg"));
+
+        Some(Self {
+            service: "youtube",
+            kind: "Video",
+            title: snippet.title.clone()?,
+            artists,
+            album: None,
+            genres: vec![],
+            release_year: snippet.published_at.as_deref().and_then(year),
+            track_count: None,
+            cover_url: Some(cover_url),
+            links: vec![
+                ("YouTube", youtube_url(id)),
+                (
+                    "YouTube Music",
+                    format!("https://music.youtube.com/watch?v={id}"),
+                ),
+            ],
+        })
+    }
+
+    // YouTube Music has no catalogue lookup to match against, so link a search instead.
+    fn add_youtube_music_search(&mut self) {
+        let query = match self.artists.first() {
+            Some(a) => format!("{} {a}", self.title),
+            None => self.title.clone(),
+        };
+
+        if let Ok(url) =
+            Url::parse_with_params("https://music.youtube.com/search", &[("q", query.as_str())])
+        {
+            self.links.push(("Search YouTube Music", url.to_string()));
+        }
+    }
+
+    fn embed(&self, with_links: bool) -> CreateEmbed {
+        let mut embed = CreateEmbed::new()
+            .title(self.title.as_str())
+            .footer(CreateEmbedFooter::new(self.kind));
+
+        if !self.artists.is_empty() {
+            embed = embed.description(self.artists.join(", "));
+        }
+
+        if let Some((_, url)) = self.links.first() {
+            embed = embed.url(url.as_str());
+        }
+
+        if let Some(cover) = &self.cover_url {
+            embed = embed.thumbnail(cover.as_str());
+        }
+
+        if let Some(y) = &self.release_year {
+            embed = embed.field("Released", y.as_str(), true);
+        }
+
+        if let Some(n) = self.track_count {
+            embed = embed.field("Tracks", n.to_string(), true);
+        }
+
+        if with_links && !self.links.is_empty() {
+            let links: Vec<String> = self
+                .links
+                .iter()
+                .map(|(service, url)| format!("[{service}]({url})"))
+                .collect();
+
+            embed = embed.field("Listen on", links.join(" · "), false);
+        }
+
+        embed
+    }
+}
+
+pub fn tidal_url(resource: &TidalResource) -> String {
+    match resource {
+        TidalResource::Album(id) => format!("https://tidal.com/browse/album/{id}"),
+        TidalResource::Track(id) => format!("https://tidal.com/browse/track/{id}"),
+    }
+}
+
+pub fn spotify_url(id: &IdType) -> String {
+    match id {
+        IdType::Album(id) => format!("https://open.spotify.com/album/{id}"),
+        IdType::Track(id) => format!("https://open.spotify.com/track/{id}"),
+    }
+}
+
+pub fn youtube_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={id}")
+}
+
+fn year(release_date: &str) -> Option<String> {
+    release_date.get(..4).map(String::from)
+}
+
+/// The embeds and components showing `cards` in one message. A lone card gets its links
+/// as buttons; with several they are listed in each embed, since buttons belong to the
+/// message rather than an embed.
+fn layout(cards: &[Card]) -> (Vec<CreateEmbed>, Vec<CreateActionRow>) {
+    let cards = &cards[..cards.len().min(MAX_EMBEDS)];
+
+    let [card] = cards else {
+        return (cards.iter().map(|c| c.embed(true)).collect(), vec![]);
+    };
+
+    if card.links.is_empty() {
+        return (vec![card.embed(false)], vec![]);
+    }
+
+    let buttons = card
+        .links
+        .iter()
+        .take(MAX_BUTTONS)
+        .map(|(service, url)| CreateButton::new_link(url.as_str()).label(*service))
+        .collect();
+
+    (
+        vec![card.embed(false)],
+        vec![CreateActionRow::Buttons(buttons)],
+    )
+}
+
+/// Builds a single reply to a message for all of its cards.
+pub fn reply(message: &Message, cards: &[Card]) -> CreateMessage {
+    let (embeds, components) = layout(cards);
+
+    CreateMessage::new()
+        .reference_message(message)
+        .allowed_mentions(CreateAllowedMentions::new().replied_user(false))
+        .embeds(embeds)
+        .components(components)
+}
+
+/// Builds a command response showing `cards`.
+pub fn command_reply(cards: &[Card]) -> CreateReply {
+    let (embeds, components) = layout(cards);
+
+    let mut reply = CreateReply::default().components(components);
+
+    for embed in embeds {
+        reply = reply.embed(embed);
+    }
+
+    reply
+}
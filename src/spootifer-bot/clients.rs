@@ -0,0 +1,153 @@
+use crate::{config, spotify, tidal};
+use chrono::{DateTime, TimeDelta, Utc};
+use prawn::client::TidalClient;
+use rspotify::ClientCredsSpotify;
+use rspotify::clients::BaseClient;
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{error, info};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// Tokens are replaced this long before they expire so in-flight lookups never use a
+// token that runs out mid-request.
+const REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(5);
+// Assumed lifetime for tokens whose expiry could not be determined.
+const FALLBACK_LIFETIME: TimeDelta = TimeDelta::minutes(30);
+
+struct Refreshable<C> {
+    client: Arc<C>,
+    expires_at: DateTime<Utc>,
+}
+
+impl<C> Refreshable<C> {
+    fn expiring(&self) -> bool {
+        self.expires_at - REFRESH_MARGIN <= Utc::now()
+    }
+}
+
+/// The app-level client-credential clients used for lookups and cross-service matching.
+/// Each client is replaced as a whole when its token is refreshed, so callers should
+/// fetch it per operation rather than holding on to it. Clients for services disabled in
+/// the config are `None`.
+pub struct AppClients {
+    spotify: Option<RwLock<Refreshable<ClientCredsSpotify>>>,
+    tidal: Option<RwLock<Refreshable<TidalClient>>>,
+}
+
+impl AppClients {
+    pub async fn init() -> Result<Self> {
+        let spotify = if config::get().spotify.is_some() {
+            Some(RwLock::new(new_spotify_client().await?))
+        } else {
+            info!("spotify is disabled");
+            None
+        };
+
+        let tidal = if config::get().tidal.is_some() {
+            Some(RwLock::new(new_tidal_client().await?))
+        } else {
+            info!("tidal is disabled");
+            None
+        };
+
+        Ok(Self { spotify, tidal })
+    }
+
+    pub fn spotify(&self) -> Option<Arc<ClientCredsSpotify>> {
+        self.spotify.as_ref().map(|s| read(s).client.clone())
+    }
+
+    pub fn tidal(&self) -> Option<Arc<TidalClient>> {
+        self.tidal.as_ref().map(|t| read(t).client.clone())
+    }
+
+    pub fn spotify_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.spotify.as_ref().map(|s| read(s).expires_at)
+    }
+
+    pub fn tidal_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.tidal.as_ref().map(|t| read(t).expires_at)
+    }
+
+    async fn refresh_expiring(&self) {
+        if let Some(spotify) = &self.spotify
+            && spotify.read().is_ok_and(|s| s.expiring())
+        {
+            match new_spotify_client().await {
+                Ok(s) => {
+                    info!(
+                        "refreshed spotify client token, expires at {}",
+                        s.expires_at
+                    );
+                    swap(spotify, s);
+                }
+                Err(e) => error!("failed to refresh spotify client token: {e}"),
+            }
+        }
+
+        if let Some(tidal) = &self.tidal
+            && tidal.read().is_ok_and(|t| t.expiring())
+        {
+            match new_tidal_client().await {
+                Ok(t) => {
+                    info!("refreshed tidal client token, expires at {}", t.expires_at);
+                    swap(tidal, t);
+                }
+                Err(e) => error!("failed to refresh tidal client token: {e}"),
+            }
+        }
+    }
+}
+
+/// Periodically replaces client-credential clients whose tokens are about to expire.
+pub async fn keep_fresh(clients: Arc<AppClients>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        clients.refresh_expiring().await;
+    }
+}
+
+fn read<C>(lock: &RwLock<Refreshable<C>>) -> std::sync::RwLockReadGuard<'_, Refreshable<C>> {
+    match lock.read() {
+        Ok(c) => c,
+        Err(e) => e.into_inner(),
+    }
+}
+
+fn swap<C>(lock: &RwLock<Refreshable<C>>, refreshed: Refreshable<C>) {
+    match lock.write() {
+        Ok(mut c) => *c = refreshed,
+        Err(e) => *e.into_inner() = refreshed,
+    }
+}
+
+async fn new_spotify_client() -> Result<Refreshable<ClientCredsSpotify>> {
+    let client = ClientCredsSpotify::new(spotify::credentials()?);
+
+    client.request_token().await?;
+
+    let expires_at = {
+        let token = client.get_token();
+        let token = token.lock().await.map_err(|e| format!("{e:?}"))?;
+
+        token.as_ref().and_then(|t| t.expires_at)
+    };
+
+    Ok(Refreshable {
+        client: Arc::new(client),
+        expires_at: expires_at.unwrap_or_else(|| Utc::now() + FALLBACK_LIFETIME),
+    })
+}
+
+async fn new_tidal_client() -> Result<Refreshable<TidalClient>> {
+    let (client, expires_at) = tidal::init_tidal_with_secret().await?;
+
+    Ok(Refreshable {
+        client: Arc::new(client),
+        expires_at: expires_at.unwrap_or_else(|| Utc::now() + FALLBACK_LIFETIME),
+    })
+}
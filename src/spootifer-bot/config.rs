@@ -0,0 +1,485 @@
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use url::Url;
+
+const CONFIG_PATH_VAR: &str = "SPOOTIFER_CONFIG";
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8081";
+const DEFAULT_INTERNAL_LISTEN_ADDR: &str = "127.0.0.1:9091";
+const DEFAULT_MAX_CONCURRENT_WRITES: usize = 4;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadError { path: PathBuf, cause: String },
+    ParseError { path: PathBuf, cause: String },
+    Invalid { problems: Vec<String> },
+    Disabled { service: &'static str },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ReadError { path, cause } => {
+                write!(f, "failed to read config file {}: {cause}", path.display())
+            }
+            Self::ParseError { path, cause } => {
+                write!(f, "failed to parse config file {}: {cause}", path.display())
+            }
+            Self::Invalid { problems } => {
+                write!(f, "invalid configuration:")?;
+                for p in problems {
+                    write!(f, "\n  - {p}")?;
+                }
+                Ok(())
+            }
+            Self::Disabled { service } => write!(f, "{service} is disabled"),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Validated configuration, loaded once at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_path: String,
+    pub discord: DiscordConfig,
+    pub server: ServerConfig,
+    pub encryption: EncryptionConfig,
+    pub processing: ProcessingConfig,
+    pub logging: LoggingConfig,
+    pub spotify: Option<SpotifyConfig>,
+    pub tidal: Option<TidalConfig>,
+    pub youtube: Option<YoutubeConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscordConfig {
+    pub bot_token: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listen_addr: String,
+    /// Serves `/readyz` and `/metrics`. Keep this off the public internet.
+    pub internal_listen_addr: String,
+    pub base_redirect_uri: Url,
+}
+
+impl ServerConfig {
+    pub fn callback_uri(&self) -> String {
+        format!(
+            "{}/callback",
+            self.base_redirect_uri.as_str().trim_end_matches('/')
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EncryptionConfig {
+    pub key: String,
+    pub previous_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessingConfig {
+    // Playlist writes that may run at once, per service.
+    pub max_concurrent_writes: usize,
+    // Whether users can archive to their own playlists as well as the server's.
+    pub personal_playlists: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    // OTLP/HTTP traces endpoint, e.g. http://localhost:4318/v1/traces.
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpotifyConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    // Redirect uri given to clients built from a stored token, which never redirect.
+    pub token_redirect_uri: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TidalConfig {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct YoutubeConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    // Data API key used to look up video details for link cards.
+    pub api_key: Option<String>,
+}
+
+/// Values passed on the command line, which take precedence over the file and env.
+#[derive(Debug, Default)]
+pub struct CliOverrides {
+    pub config_path: Option<PathBuf>,
+    pub database_path: Option<String>,
+    pub listen_addr: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    database_path: Option<String>,
+    discord: RawDiscord,
+    server: RawServer,
+    encryption: RawEncryption,
+    processing: RawProcessing,
+    logging: RawLogging,
+    spotify: RawService,
+    tidal: RawService,
+    youtube: RawService,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawDiscord {
+    bot_token: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawServer {
+    listen_addr: Option<String>,
+    internal_listen_addr: Option<String>,
+    base_redirect_uri: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawEncryption {
+    key: Option<String>,
+    previous_keys: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawProcessing {
+    max_concurrent_writes: Option<usize>,
+    personal_playlists: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawLogging {
+    format: Option<String>,
+    otlp_endpoint: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawService {
+    enabled: Option<bool>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    redirect_uri: Option<String>,
+    api_key: Option<String>,
+}
+
+impl RawConfig {
+    fn apply_env(&mut self) {
+        env_override(&mut self.database_path, "DATABASE_PATH");
+        env_override(&mut self.discord.bot_token, "DISCORD_BOT_TOKEN");
+        env_override(&mut self.server.listen_addr, "LISTEN_ADDR");
+        env_override(
+            &mut self.server.internal_listen_addr,
+            "INTERNAL_LISTEN_ADDR",
+        );
+        env_override(&mut self.server.base_redirect_uri, "BASE_REDIRECT_URI");
+        env_override(&mut self.encryption.key, "TOKEN_ENCRYPTION_KEY");
+
+        if let Ok(keys) = env::var("TOKEN_ENCRYPTION_PREVIOUS_KEYS") {
+            self.encryption.previous_keys = Some(
+                keys.split(',')
+                    .map(str::trim)
+                    .filter(|k| !k.is_empty())
+                    .map(String::from)
+                    .collect(),
+            );
+        }
+
+        env_override(&mut self.logging.format, "LOG_FORMAT");
+        env_override(
+            &mut self.logging.otlp_endpoint,
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        );
+
+        self.spotify.apply_env("SPOTIFY", "RSPOTIFY");
+        env_override(&mut self.spotify.redirect_uri, "SPOTIFY_REDIRECT_URI");
+        self.tidal.apply_env("TIDAL", "TIDAL");
+        self.youtube.apply_env("YOUTUBE", "YOUTUBE");
+        env_override(&mut self.youtube.api_key, "YOUTUBE_API_KEY");
+    }
+
+    fn apply_cli(&mut self, cli: &CliOverrides) {
+        if let Some(p) = &cli.database_path {
+            self.database_path = Some(p.clone());
+        }
+
+        if let Some(a) = &cli.listen_addr {
+            self.server.listen_addr = Some(a.clone());
+        }
+    }
+
+    fn validate(self) -> Result<Config, ConfigError> {
+        let mut problems = vec![];
+
+        let database_path = required(self.database_path, "database_path", &mut problems);
+        let bot_token = required(self.discord.bot_token, "discord.bot_token", &mut problems);
+        let key = required(self.encryption.key, "encryption.key", &mut problems);
+
+        let base_redirect_uri = match self.server.base_redirect_uri {
+            Some(u) => match Url::parse(u.as_str()) {
+                Ok(u) => Some(u),
+                Err(e) => {
+                    problems.push(format!("server.base_redirect_uri {u} is not a url: {e}"));
+                    None
+                }
+            },
+            None => {
+                problems.push(String::from(
+                    "server.base_redirect_uri is required (or set BASE_REDIRECT_URI)",
+                ));
+                None
+            }
+        };
+
+        let max_concurrent_writes = self
+            .processing
+            .max_concurrent_writes
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_WRITES);
+
+        if max_concurrent_writes == 0 {
+            problems.push(String::from(
+                "processing.max_concurrent_writes must be at least 1",
+            ));
+        }
+
+        let format = match self.logging.format.as_deref().map(str::to_lowercase) {
+            None => LogFormat::default(),
+            Some(f) if f == "text" => LogFormat::Text,
+            Some(f) if f == "json" => LogFormat::Json,
+            Some(f) => {
+                problems.push(format!(
+                    "logging.format must be \"text\" or \"json\", got \"{f}\""
+                ));
+                LogFormat::default()
+            }
+        };
+
+        let spotify = self.spotify.credentials("spotify", &mut problems).map(
+            |(client_id, client_secret, redirect_uri)| SpotifyConfig {
+                client_id,
+                client_secret,
+                token_redirect_uri: redirect_uri,
+            },
+        );
+
+        let tidal =
+            self.tidal
+                .credentials("tidal", &mut problems)
+                .map(|(client_id, client_secret, _)| TidalConfig {
+                    client_id,
+                    client_secret,
+                });
+
+        let youtube_api_key = self.youtube.api_key.clone();
+        let youtube = self.youtube.credentials("youtube", &mut problems).map(
+            |(client_id, client_secret, _)| YoutubeConfig {
+                client_id,
+                client_secret,
+                api_key: youtube_api_key,
+            },
+        );
+
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid { problems });
+        }
+
+        let (Some(database_path), Some(bot_token), Some(key), Some(base_redirect_uri)) =
+            (database_path, bot_token, key, base_redirect_uri)
+        else {
+            return Err(ConfigError::Invalid { problems });
+        };
+
+        Ok(Config {
+            database_path,
+            discord: DiscordConfig { bot_token },
+            server: ServerConfig {
+                listen_addr: self
+                    .server
+                    .listen_addr
+                    .unwrap_or_else(|| String::from(DEFAULT_LISTEN_ADDR)),
+                internal_listen_addr: self
+                    .server
+                    .internal_listen_addr
+                    .unwrap_or_else(|| String::from(DEFAULT_INTERNAL_LISTEN_ADDR)),
+                base_redirect_uri,
+            },
+            encryption: EncryptionConfig {
+                key,
+                previous_keys: self.encryption.previous_keys.unwrap_or_default(),
+            },
+            processing: ProcessingConfig {
+                max_concurrent_writes,
+                personal_playlists: self.processing.personal_playlists.unwrap_or(true),
+            },
+            logging: LoggingConfig {
+                format,
+                otlp_endpoint: self.logging.otlp_endpoint.filter(|e| !e.trim().is_empty()),
+            },
+            spotify,
+            tidal,
+            youtube,
+        })
+    }
+}
+
+impl RawService {
+    fn apply_env(&mut self, prefix: &str, credential_prefix: &str) {
+        if let Ok(enabled) = env::var(format!("{prefix}_ENABLED")) {
+            self.enabled = Some(!matches!(
+                enabled.to_lowercase().as_str(),
+                "false" | "0" | "no" | "off"
+            ));
+        }
+
+        env_override(
+            &mut self.client_id,
+            &format!("{credential_prefix}_CLIENT_ID"),
+        );
+        env_override(
+            &mut self.client_secret,
+            &format!("{credential_prefix}_CLIENT_SECRET"),
+        );
+    }
+
+    /// Returns the service's credentials, or `None` if it is disabled. Services are
+    /// enabled unless `enabled = false`, in which case no credentials are needed.
+    fn credentials(
+        self,
+        service: &str,
+        problems: &mut Vec<String>,
+    ) -> Option<(String, String, Option<String>)> {
+        if self.enabled == Some(false) {
+            return None;
+        }
+
+        let client_id = required(
+            self.client_id,
+            format!("{service}.client_id").as_str(),
+            problems,
+        );
+        let client_secret = required(
+            self.client_secret,
+            format!("{service}.client_secret").as_str(),
+            problems,
+        );
+
+        if client_id.is_none() || client_secret.is_none() {
+            problems.push(format!(
+                "set {service}.enabled = false to run without {service}"
+            ));
+        }
+
+        Some((client_id?, client_secret?, self.redirect_uri))
+    }
+}
+
+fn env_override(field: &mut Option<String>, var: &str) {
+    if let Ok(v) = env::var(var) {
+        *field = Some(v);
+    }
+}
+
+fn required(value: Option<String>, name: &str, problems: &mut Vec<String>) -> Option<String> {
+    match value {
+        Some(v) if !v.trim().is_empty() => Some(v),
+        _ => {
+            problems.push(format!("{name} is required"));
+            None
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<RawConfig, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::ReadError {
+        path: path.to_path_buf(),
+        cause: e.to_string(),
+    })?;
+
+    toml::from_str(contents.as_str()).map_err(|e| ConfigError::ParseError {
+        path: path.to_path_buf(),
+        cause: e.to_string(),
+    })
+}
+
+/// Loads configuration from the TOML file given by `--config` or `SPOOTIFER_CONFIG`,
+/// then env vars, then command line flags, each layer overriding the one before.
+pub fn load(cli: &CliOverrides) -> Result<Config, ConfigError> {
+    let path = cli
+        .config_path
+        .clone()
+        .or_else(|| env::var(CONFIG_PATH_VAR).ok().map(PathBuf::from));
+
+    let mut raw = match path {
+        Some(p) => read_file(p.as_path())?,
+        None => RawConfig::default(),
+    };
+
+    raw.apply_env();
+    raw.apply_cli(cli);
+
+    raw.validate()
+}
+
+/// Stores the loaded configuration for the rest of the process.
+pub fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("config not loaded")
+}
+
+pub fn spotify() -> Result<&'static SpotifyConfig, ConfigError> {
+    get()
+        .spotify
+        .as_ref()
+        .ok_or(ConfigError::Disabled { service: "spotify" })
+}
+
+pub fn tidal() -> Result<&'static TidalConfig, ConfigError> {
+    get()
+        .tidal
+        .as_ref()
+        .ok_or(ConfigError::Disabled { service: "tidal" })
+}
+
+pub fn youtube() -> Result<&'static YoutubeConfig, ConfigError> {
+    get()
+        .youtube
+        .as_ref()
+        .ok_or(ConfigError::Disabled { service: "youtube" })
+}
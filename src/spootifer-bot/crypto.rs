@@ -0,0 +1,212 @@
+use crate::config::EncryptionConfig;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+
+// Values written by this module look like `enc:v1:<wrapped data key>:<ciphertext>`.
+// Anything without the prefix is treated as a legacy plaintext value.
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+static CIPHER: OnceLock<TokenCipher> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub enum CryptoError {
+    KeyError { cause: String },
+    EncryptError,
+    DecryptError { cause: String },
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KeyError { cause } => write!(f, "invalid encryption key: {cause}"),
+            Self::EncryptError => write!(f, "failed to encrypt value"),
+            Self::DecryptError { cause } => write!(f, "failed to decrypt value: {cause}"),
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+type Result<T> = std::result::Result<T, CryptoError>;
+
+/// Envelope encryption for secrets stored in the database. Every value gets its own
+/// data key, which is wrapped with the configured key encryption key. Previous keys are
+/// only used to unwrap.
+pub struct TokenCipher {
+    current: Aes256Gcm,
+    previous: Vec<Aes256Gcm>,
+}
+
+impl TokenCipher {
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self> {
+        Self::new(
+            config.key.as_str(),
+            config.previous_keys.iter().map(String::as_str).collect(),
+        )
+    }
+
+    pub fn new(current: &str, previous: Vec<&str>) -> Result<Self> {
+        Ok(Self {
+            current: key_from_base64(current)?,
+            previous: previous
+                .into_iter()
+                .map(key_from_base64)
+                .collect::<Result<Vec<Aes256Gcm>>>()?,
+        })
+    }
+
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let data_cipher = Aes256Gcm::new(&data_key);
+
+        let wrapped_key = seal(&self.current, data_key.as_slice())?;
+        let ciphertext = seal(&data_cipher, plaintext.as_bytes())?;
+
+        Ok(format!(
+            "{ENCRYPTED_PREFIX}{}:{}",
+            STANDARD.encode(wrapped_key),
+            STANDARD.encode(ciphertext)
+        ))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let Some((wrapped_key, ciphertext)) = split_encrypted(value)? else {
+            return Ok(value.to_string());
+        };
+
+        let data_key = self.unwrap_data_key(&wrapped_key)?;
+        let data_cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key));
+
+        let plaintext = open(&data_cipher, &ciphertext)?;
+
+        String::from_utf8(plaintext).map_err(|e| CryptoError::DecryptError {
+            cause: e.to_string(),
+        })
+    }
+
+    /// Re-wraps the data key of an encrypted value with the current key, leaving the
+    /// ciphertext untouched. Plaintext values are encrypted.
+    pub fn rewrap(&self, value: &str) -> Result<String> {
+        let Some((wrapped_key, ciphertext)) = split_encrypted(value)? else {
+            return self.encrypt(value);
+        };
+
+        let data_key = self.unwrap_data_key(&wrapped_key)?;
+        let rewrapped_key = seal(&self.current, &data_key)?;
+
+        Ok(format!(
+            "{ENCRYPTED_PREFIX}{}:{}",
+            STANDARD.encode(rewrapped_key),
+            STANDARD.encode(ciphertext)
+        ))
+    }
+
+    fn unwrap_data_key(&self, wrapped_key: &[u8]) -> Result<Vec<u8>> {
+        std::iter::once(&self.current)
+            .chain(self.previous.iter())
+            .find_map(|k| open(k, wrapped_key).ok())
+            .ok_or_else(|| CryptoError::DecryptError {
+                cause: String::from("no configured key could unwrap the data key"),
+            })
+    }
+}
+
+pub fn cipher() -> Result<&'static TokenCipher> {
+    if let Some(c) = CIPHER.get() {
+        return Ok(c);
+    }
+
+    let c = TokenCipher::from_config(&crate::config::get().encryption)?;
+
+    Ok(CIPHER.get_or_init(|| c))
+}
+
+pub fn encrypt(plaintext: &str) -> Result<String> {
+    cipher()?.encrypt(plaintext)
+}
+
+pub fn decrypt(value: &str) -> Result<String> {
+    cipher()?.decrypt(value)
+}
+
+pub fn encrypt_optional(plaintext: Option<String>) -> Result<Option<String>> {
+    plaintext.map(|p| encrypt(p.as_str())).transpose()
+}
+
+pub fn decrypt_optional(value: Option<String>) -> Result<Option<String>> {
+    value.map(|v| decrypt(v.as_str())).transpose()
+}
+
+fn key_from_base64(encoded: &str) -> Result<Aes256Gcm> {
+    let bytes = STANDARD
+        .decode(encoded)
+        .map_err(|e| CryptoError::KeyError {
+            cause: e.to_string(),
+        })?;
+
+    if bytes.len() != KEY_LEN {
+        return Err(CryptoError::KeyError {
+            cause: format!("expected {KEY_LEN} bytes, got {}", bytes.len()),
+        });
+    }
+
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)))
+}
+
+fn split_encrypted(value: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let Some(rest) = value.strip_prefix(ENCRYPTED_PREFIX) else {
+        return Ok(None);
+    };
+
+    let Some((wrapped_key, ciphertext)) = rest.split_once(':') else {
+        return Err(CryptoError::DecryptError {
+            cause: String::from("malformed encrypted value"),
+        });
+    };
+
+    let decode = |s: &str| {
+        STANDARD.decode(s).map_err(|e| CryptoError::DecryptError {
+            cause: e.to_string(),
+        })
+    };
+
+    Ok(Some((decode(wrapped_key)?, decode(ciphertext)?)))
+}
+
+// Output is the nonce followed by the ciphertext and tag.
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| CryptoError::EncryptError)?;
+
+    Ok([nonce.as_slice(), ciphertext.as_slice()].concat())
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::DecryptError {
+            cause: String::from("value too short"),
+        });
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| CryptoError::DecryptError {
+            cause: String::from("authentication failed"),
+        })
+}
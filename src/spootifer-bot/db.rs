@@ -1,9 +1,11 @@
+use crate::crypto::{self, TokenCipher};
 use chrono::Utc;
 use refinery::{Report, embed_migrations};
 use rusqlite::{Connection, Row, Transaction};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::info;
 
 embed_migrations!("src/spootifer-bot/migrations");
 
@@ -11,6 +13,8 @@ embed_migrations!("src/spootifer-bot/migrations");
 pub struct User {
     pub id: Option<i64>,
     pub discord_user_id: String,
+    // Only archive messages the user picks with "Add to my playlists".
+    pub opt_in_only: bool,
     pub deleted_at: Option<String>,
     pub created_at: String,
     pub updated_at: String,
@@ -40,12 +44,110 @@ pub struct UserGuild {
     pub for_service: String,
 }
 
+/// The server's own playlist for a service, written to with its owner's token.
+#[derive(Clone)]
+pub struct GuildPlaylist {
+    pub discord_guild_id: String,
+    pub for_service: String,
+    pub playlist_id: String,
+    pub owner_user_id: i64,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// Per guild overrides of the bot's behaviour. `None` means the default is used.
+#[derive(Clone, Default)]
+pub struct GuildSettings {
+    pub discord_guild_id: String,
+    pub spotify_reaction: Option<String>,
+    pub tidal_reaction: Option<String>,
+    pub youtube_reaction: Option<String>,
+    pub album_art: Option<bool>,
+    pub expand_albums: Option<bool>,
+    pub ignore_bots: Option<bool>,
+    pub digest_channel_id: Option<String>,
+}
+
+/// Allows or denies link ingestion in a channel, or every channel in a category.
+#[derive(Clone)]
+pub struct ChannelRule {
+    pub discord_guild_id: String,
+    pub channel_id: String,
+    pub allow: bool,
+}
+
+/// A manually chosen match for a resource on another service, used instead of the
+/// automatic matcher.
+#[derive(Clone)]
+pub struct MatchOverride {
+    pub source_service: String,
+    pub source_kind: String,
+    pub source_id: String,
+    pub target_service: String,
+    pub target_kind: String,
+    pub target_id: String,
+    pub created_by_user_id: i64,
+}
+
+/// An album, track or video posted in a guild, kept for archive statistics.
+#[derive(Clone)]
+pub struct MessageLink {
+    pub link: String,
+    pub message_id: String,
+    pub guild_id: String,
+    pub channel_id: String,
+    pub author_discord_user_id: String,
+    pub service: String,
+    // "album", "track" or "video".
+    pub link_type: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genres: Vec<String>,
+    pub track_count: Option<u32>,
+}
+
+/// A count of posted links, and the tracks in them, for one value of whatever they
+/// were grouped by.
+pub struct ArchiveCount {
+    pub key: String,
+    pub links: i64,
+    pub tracks: i64,
+}
+
+/// What `count_message_links_by` ranks its groups by.
+#[derive(Clone, Copy)]
+pub enum RankBy {
+    Links,
+    // Albums count as all of their tracks.
+    Tracks,
+}
+
+impl RankBy {
+    const fn sql(self) -> &'static str {
+        match self {
+            Self::Links => "COUNT(*)",
+            Self::Tracks => "SUM(COALESCE(track_count, 1))",
+        }
+    }
+}
+
+/// A guild that wants a weekly digest, and when it last got one, or turned it on.
+pub struct DigestGuild {
+    pub discord_guild_id: String,
+    pub channel_id: String,
+    pub sent_at: Option<String>,
+}
+
 pub struct AuthRequest {
     pub discord_user_id: String,
     pub state: String,
     pub pkce_code_verifier: Option<String>,
     pub pkce_code_challenge: Option<String>,
     pub for_service: String,
+    pub discord_guild_id: Option<String>,
+    pub discord_guild_name: Option<String>,
+    pub interaction_token: Option<String>,
 }
 
 pub struct DbError;
@@ -64,6 +166,13 @@ impl Display for DbError {
 
 impl Error for DbError {}
 
+// Waits for the connection instead of failing when another task holds it. Used for the
+// reads and writes every message makes, where a spurious failure would drop or
+// misroute it, or leave it out of the stats.
+fn wait_for_connection(conn: &Arc<Mutex<Connection>>) -> Result<MutexGuard<'_, Connection>> {
+    conn.lock().map_err(|_| DbError.into())
+}
+
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 pub fn run_migrations(mut conn: Mutex<Connection>) -> Result<Report> {
@@ -71,10 +180,99 @@ pub fn run_migrations(mut conn: Mutex<Connection>) -> Result<Report> {
         return Err(DbError.into());
     };
 
-    match migrations::runner().run(c) {
-        Ok(r) => Ok(r),
-        Err(e) => Err(e.into()),
+    let report = match migrations::runner().run(c) {
+        Ok(r) => r,
+        Err(e) => return Err(e.into()),
+    };
+
+    // Refinery migrations are plain SQL, so existing plaintext secrets are encrypted
+    // here once the schema is up to date. Already encrypted values are left alone.
+    let encrypted = reencrypt_secrets(c, |cipher, value| {
+        if TokenCipher::is_encrypted(value) {
+            return Ok(None);
+        }
+
+        Ok(Some(cipher.encrypt(value)?))
+    })?;
+
+    info!("encrypted {encrypted} plaintext secrets");
+
+    Ok(report)
+}
+
+pub fn rotate_encryption_key(mut conn: Mutex<Connection>) -> Result<usize> {
+    let Ok(c) = conn.get_mut() else {
+        return Err(DbError.into());
+    };
+
+    reencrypt_secrets(c, |cipher, value| Ok(Some(cipher.rewrap(value)?)))
+}
+
+// Applies `f` to every stored secret in a single transaction, writing back any value
+// it returns. Returns the number of values rewritten.
+fn reencrypt_secrets(
+    c: &mut Connection,
+    f: impl Fn(&TokenCipher, &str) -> Result<Option<String>>,
+) -> Result<usize> {
+    let cipher = crypto::cipher()?;
+    let tx = c.transaction()?;
+    let mut rewritten = 0;
+
+    for table in ["oauth_tokens", "oauth_token_history"] {
+        let tokens = tx
+            .prepare(format!("SELECT id, access_token, refresh_token FROM {table}").as_str())?
+            .query_map(
+                [],
+                |r| -> rusqlite::Result<(i64, Option<String>, Option<String>)> {
+                    Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<(i64, Option<String>, Option<String>)>>>()?;
+
+        for (id, access_token, refresh_token) in tokens {
+            if let Some(Some(v)) = access_token.map(|t| f(cipher, t.as_str())).transpose()? {
+                tx.execute(
+                    format!("UPDATE {table} SET access_token = ? WHERE id = ?").as_str(),
+                    (v, id),
+                )?;
+                rewritten += 1;
+            }
+
+            if let Some(Some(v)) = refresh_token.map(|t| f(cipher, t.as_str())).transpose()? {
+                tx.execute(
+                    format!("UPDATE {table} SET refresh_token = ? WHERE id = ?").as_str(),
+                    (v, id),
+                )?;
+                rewritten += 1;
+            }
+        }
     }
+
+    for column in ["pkce_code_verifier", "interaction_token"] {
+        let values = tx
+            .prepare(
+                format!("SELECT rowid, {column} FROM auth_requests WHERE {column} IS NOT NULL")
+                    .as_str(),
+            )?
+            .query_map([], |r| -> rusqlite::Result<(i64, String)> {
+                Ok((r.get(0)?, r.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+
+        for (rowid, value) in values {
+            if let Some(v) = f(cipher, value.as_str())? {
+                tx.execute(
+                    format!("UPDATE auth_requests SET {column} = ? WHERE rowid = ?").as_str(),
+                    (v, rowid),
+                )?;
+                rewritten += 1;
+            }
+        }
+    }
+
+    tx.commit()?;
+
+    Ok(rewritten)
 }
 
 pub fn get_user_guilds_by_guild_id_and_service(
@@ -82,9 +280,7 @@ pub fn get_user_guilds_by_guild_id_and_service(
     guild_id: &str,
     service: &str,
 ) -> Result<Vec<UserGuild>> {
-    let Ok(c) = conn.try_lock() else {
-        return Err(DbError.into());
-    };
+    let c = wait_for_connection(conn)?;
 
     let q = c.prepare("SELECT user_id, discord_guild_id, playlist_id, deleted_at, created_at, updated_at, for_service FROM user_guilds WHERE discord_guild_id = ? AND for_service = ?");
 
@@ -156,6 +352,7 @@ pub fn first_or_create_user_by_discord_user_id(
     Ok(User {
         id: Some(r),
         discord_user_id: discord_user_id.to_string(),
+        opt_in_only: false,
         created_at: now.clone(),
         updated_at: now.clone(),
         deleted_at: None,
@@ -234,12 +431,10 @@ pub fn get_user_guild_by_user_id_and_guild_id_and_service(
 }
 
 pub fn get_user_by_user_id(conn: &Arc<Mutex<Connection>>, user_id: i64) -> Result<User> {
-    let Ok(c) = conn.try_lock() else {
-        return Err(DbError.into());
-    };
+    let c = wait_for_connection(conn)?;
 
     let q = c.prepare(
-        "SELECT id, discord_user_id, deleted_at, created_at, updated_at FROM users WHERE id = ?;",
+        "SELECT id, discord_user_id, deleted_at, created_at, updated_at, opt_in_only FROM users WHERE id = ?;",
     );
 
     let r = q?.query_row([user_id], |r: &Row| -> rusqlite::Result<User> {
@@ -249,6 +444,7 @@ pub fn get_user_by_user_id(conn: &Arc<Mutex<Connection>>, user_id: i64) -> Resul
             deleted_at: r.get(2)?,
             created_at: r.get(3)?,
             updated_at: r.get(4)?,
+            opt_in_only: r.get(5)?,
         })
     });
 
@@ -266,7 +462,7 @@ pub fn get_user_by_discord_user_id(
         return Err(DbError.into());
     };
 
-    let q = c.prepare("SELECT id, discord_user_id, deleted_at, created_at, updated_at FROM users WHERE discord_user_id = ?;");
+    let q = c.prepare("SELECT id, discord_user_id, deleted_at, created_at, updated_at, opt_in_only FROM users WHERE discord_user_id = ?;");
 
     let r = q?.query_row([discord_user_id], |r: &Row| -> rusqlite::Result<User> {
         Ok(User {
@@ -275,6 +471,7 @@ pub fn get_user_by_discord_user_id(
             deleted_at: r.get(2)?,
             created_at: r.get(3)?,
             updated_at: r.get(4)?,
+            opt_in_only: r.get(5)?,
         })
     });
 
@@ -284,35 +481,48 @@ pub fn get_user_by_discord_user_id(
     }
 }
 
+pub fn update_user_opt_in_only(
+    conn: &Arc<Mutex<Connection>>,
+    user_id: i64,
+    opt_in_only: bool,
+) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare("UPDATE users SET opt_in_only = ?, updated_at = ? WHERE id = ?")?;
+
+    let r = q.execute((opt_in_only, Utc::now().to_string(), user_id))?;
+
+    if r > 0 {
+        return Ok(());
+    }
+
+    Err(DbError.into())
+}
+
 pub fn create_auth_request(
     conn: &Arc<Mutex<Connection>>,
-    state: String,
-    discord_user_id: &str,
-    pkce_code_challenge: Option<String>,
-    pkce_code_verifier: Option<String>,
-    for_service: &str,
+    auth_request: AuthRequest,
 ) -> Result<AuthRequest> {
     let Ok(c) = conn.try_lock() else {
         return Err(DbError.into());
     };
 
-    let mut q = c.prepare("INSERT INTO auth_requests(state, discord_user_id, pkce_code_challenge, pkce_code_verifier, for_service) VALUES(?,?,?,?,?)")?;
+    let mut q = c.prepare("INSERT INTO auth_requests(state, discord_user_id, pkce_code_challenge, pkce_code_verifier, for_service, discord_guild_id, discord_guild_name, interaction_token) VALUES(?,?,?,?,?,?,?,?)")?;
 
     _ = q.insert((
-        state.clone(),
-        discord_user_id,
-        pkce_code_challenge.clone(),
-        pkce_code_verifier.clone(),
-        for_service,
+        auth_request.state.as_str(),
+        auth_request.discord_user_id.as_str(),
+        auth_request.pkce_code_challenge.clone(),
+        crypto::encrypt_optional(auth_request.pkce_code_verifier.clone())?,
+        auth_request.for_service.as_str(),
+        auth_request.discord_guild_id.clone(),
+        auth_request.discord_guild_name.clone(),
+        crypto::encrypt_optional(auth_request.interaction_token.clone())?,
     ))?;
 
-    Ok(AuthRequest {
-        discord_user_id: discord_user_id.to_string(),
-        state,
-        pkce_code_challenge,
-        pkce_code_verifier,
-        for_service: String::from(for_service),
-    })
+    Ok(auth_request)
 }
 
 pub fn get_auth_request_by_state(
@@ -324,15 +534,18 @@ pub fn get_auth_request_by_state(
     };
 
     c.query_row_and_then(
-        "SELECT discord_user_id, state, pkce_code_challenge, pkce_code_verifier, for_service FROM auth_requests WHERE state = ?",
+        "SELECT discord_user_id, state, pkce_code_challenge, pkce_code_verifier, for_service, discord_guild_id, discord_guild_name, interaction_token FROM auth_requests WHERE state = ?",
         [discord_user_id],
         |r| -> Result<AuthRequest> {
             Ok(AuthRequest {
                 discord_user_id: r.get(0)?,
                 state: r.get(1)?,
                 pkce_code_challenge: r.get(2)?,
-                pkce_code_verifier: r.get(3)?,
-                for_service: r.get(4)?
+                pkce_code_verifier: crypto::decrypt_optional(r.get(3)?)?,
+                for_service: r.get(4)?,
+                discord_guild_id: r.get(5)?,
+                discord_guild_name: r.get(6)?,
+                interaction_token: crypto::decrypt_optional(r.get(7)?)?,
             })
         },
     )
@@ -364,27 +577,614 @@ pub fn get_oauth_token_by_user_id_and_service(
     });
 
     match r {
-        Ok(t) => Ok(t),
+        Ok(t) => Ok(OAuthToken {
+            access_token: crypto::decrypt(t.access_token.as_str())?,
+            refresh_token: crypto::decrypt_optional(t.refresh_token.clone())?,
+            ..t
+        }),
         Err(e) => Err(e.into()),
     }
 }
 
-pub fn insert_oauth_token(conn: &Transaction, token: OAuthToken) -> Result<i64> {
-    let q = conn.prepare("INSERT INTO oauth_tokens (user_id, refresh_token, access_token, expiry_time, token_type, created_at, updated_at, for_service) VALUES (?, ?, ?, ?, ?, ?, ?, ?)");
+/// Stores the token as the current token for its user and service, replacing any
+/// previous one. Every token written is also appended to `oauth_token_history`.
+pub fn upsert_oauth_token(conn: &Transaction, token: OAuthToken) -> Result<i64> {
+    let refresh_token = crypto::encrypt_optional(token.refresh_token)?;
+    let access_token = crypto::encrypt(token.access_token.as_str())?;
+
+    let mut history = conn.prepare("INSERT INTO oauth_token_history (user_id, refresh_token, access_token, expiry_time, token_type, created_at, updated_at, for_service) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")?;
 
-    let r = q?.insert((
+    history.insert((
         token.user_id,
-        token.refresh_token,
-        token.access_token,
-        token.expiry_time,
-        token.token_type,
-        token.created_at,
-        token.updated_at,
-        token.for_service,
-    ));
+        refresh_token.clone(),
+        access_token.clone(),
+        token.expiry_time.clone(),
+        token.token_type.clone(),
+        token.created_at.clone(),
+        token.updated_at.clone(),
+        token.for_service.clone(),
+    ))?;
+
+    let mut q = conn.prepare(
+        "INSERT INTO oauth_tokens (user_id, refresh_token, access_token, expiry_time, token_type, created_at, updated_at, for_service) VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (user_id, for_service) DO UPDATE SET
+            refresh_token = COALESCE(excluded.refresh_token, oauth_tokens.refresh_token),
+            access_token = excluded.access_token,
+            expiry_time = excluded.expiry_time,
+            token_type = excluded.token_type,
+            updated_at = excluded.updated_at,
+            deleted_at = NULL
+        RETURNING id",
+    )?;
+
+    let r = q.query_row(
+        (
+            token.user_id,
+            refresh_token,
+            access_token,
+            token.expiry_time,
+            token.token_type,
+            token.created_at,
+            token.updated_at,
+            token.for_service,
+        ),
+        |r| -> rusqlite::Result<i64> { r.get(0) },
+    );
 
     match r {
         Ok(i) => Ok(i),
         Err(e) => Err(e.into()),
     }
 }
+
+pub fn upsert_match_override(conn: &Arc<Mutex<Connection>>, o: &MatchOverride) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare(
+        "INSERT INTO match_overrides (source_service, source_kind, source_id, target_service, target_kind, target_id, created_by_user_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (source_service, source_id, target_service) DO UPDATE SET
+            source_kind = excluded.source_kind,
+            target_kind = excluded.target_kind,
+            target_id = excluded.target_id,
+            created_by_user_id = excluded.created_by_user_id,
+            updated_at = excluded.updated_at,
+            deleted_at = NULL",
+    )?;
+
+    let now = Utc::now().to_string();
+    q.execute((
+        o.source_service.as_str(),
+        o.source_kind.as_str(),
+        o.source_id.as_str(),
+        o.target_service.as_str(),
+        o.target_kind.as_str(),
+        o.target_id.as_str(),
+        o.created_by_user_id,
+        now.as_str(),
+        now.as_str(),
+    ))?;
+
+    Ok(())
+}
+
+pub fn get_match_override(
+    conn: &Arc<Mutex<Connection>>,
+    source_service: &str,
+    source_id: &str,
+    target_service: &str,
+) -> Result<Option<MatchOverride>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare("SELECT source_service, source_kind, source_id, target_service, target_kind, target_id, created_by_user_id FROM match_overrides WHERE source_service = ? AND source_id = ? AND target_service = ? AND deleted_at IS NULL")?;
+
+    let r = q.query_row(
+        (source_service, source_id, target_service),
+        |r| -> rusqlite::Result<MatchOverride> {
+            Ok(MatchOverride {
+                source_service: r.get(0)?,
+                source_kind: r.get(1)?,
+                source_id: r.get(2)?,
+                target_service: r.get(3)?,
+                target_kind: r.get(4)?,
+                target_id: r.get(5)?,
+                created_by_user_id: r.get(6)?,
+            })
+        },
+    );
+
+    match r {
+        Ok(o) => Ok(Some(o)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn get_guild_playlist_by_guild_id_and_service(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    service: &str,
+) -> Result<Option<GuildPlaylist>> {
+    let c = wait_for_connection(conn)?;
+
+    let mut q = c.prepare("SELECT discord_guild_id, for_service, playlist_id, owner_user_id, created_at, updated_at FROM guild_playlists WHERE discord_guild_id = ? AND for_service = ? AND deleted_at IS NULL")?;
+
+    let r = q.query_row(
+        (guild_id, service),
+        |r| -> rusqlite::Result<GuildPlaylist> {
+            Ok(GuildPlaylist {
+                discord_guild_id: r.get(0)?,
+                for_service: r.get(1)?,
+                playlist_id: r.get(2)?,
+                owner_user_id: r.get(3)?,
+                created_at: r.get(4)?,
+                updated_at: r.get(5)?,
+            })
+        },
+    );
+
+    match r {
+        Ok(p) => Ok(Some(p)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Registers `playlist_id` as the server's playlist for the service, replacing any
+/// previous one. Whoever registers it becomes its owner.
+pub fn upsert_guild_playlist(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    service: &str,
+    playlist_id: &str,
+    owner_user_id: i64,
+) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare(
+        "INSERT INTO guild_playlists (discord_guild_id, for_service, playlist_id, owner_user_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (discord_guild_id, for_service) DO UPDATE SET
+            playlist_id = excluded.playlist_id,
+            owner_user_id = excluded.owner_user_id,
+            updated_at = excluded.updated_at,
+            deleted_at = NULL",
+    )?;
+
+    let now = Utc::now().to_string();
+    q.execute((
+        guild_id,
+        service,
+        playlist_id,
+        owner_user_id,
+        now.as_str(),
+        now.as_str(),
+    ))?;
+
+    Ok(())
+}
+
+pub fn update_guild_playlist_owner(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    service: &str,
+    owner_user_id: i64,
+) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare("UPDATE guild_playlists SET owner_user_id = ?, updated_at = ? WHERE discord_guild_id = ? AND for_service = ? AND deleted_at IS NULL")?;
+
+    let r = q.execute((owner_user_id, Utc::now().to_string(), guild_id, service))?;
+
+    if r > 0 {
+        return Ok(());
+    }
+
+    Err(DbError.into())
+}
+
+pub fn get_guild_settings_by_guild_id(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+) -> Result<Option<GuildSettings>> {
+    let c = wait_for_connection(conn)?;
+
+    let mut q = c.prepare("SELECT discord_guild_id, spotify_reaction, tidal_reaction, youtube_reaction, album_art, expand_albums, ignore_bots, digest_channel_id FROM guild_settings WHERE discord_guild_id = ?")?;
+
+    let r = q.query_row([guild_id], |r| -> rusqlite::Result<GuildSettings> {
+        Ok(GuildSettings {
+            discord_guild_id: r.get(0)?,
+            spotify_reaction: r.get(1)?,
+            tidal_reaction: r.get(2)?,
+            youtube_reaction: r.get(3)?,
+            album_art: r.get(4)?,
+            expand_albums: r.get(5)?,
+            ignore_bots: r.get(6)?,
+            digest_channel_id: r.get(7)?,
+        })
+    });
+
+    match r {
+        Ok(s) => Ok(Some(s)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn upsert_guild_settings(
+    conn: &Arc<Mutex<Connection>>,
+    settings: &GuildSettings,
+) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare(
+        "INSERT INTO guild_settings (discord_guild_id, spotify_reaction, tidal_reaction, youtube_reaction, album_art, expand_albums, ignore_bots, digest_channel_id, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (discord_guild_id) DO UPDATE SET
+            spotify_reaction = excluded.spotify_reaction,
+            tidal_reaction = excluded.tidal_reaction,
+            youtube_reaction = excluded.youtube_reaction,
+            album_art = excluded.album_art,
+            expand_albums = excluded.expand_albums,
+            ignore_bots = excluded.ignore_bots,
+            digest_channel_id = excluded.digest_channel_id,
+            updated_at = excluded.updated_at",
+    )?;
+
+    let now = Utc::now().to_string();
+    q.execute((
+        settings.discord_guild_id.as_str(),
+        settings.spotify_reaction.as_deref(),
+        settings.tidal_reaction.as_deref(),
+        settings.youtube_reaction.as_deref(),
+        settings.album_art,
+        settings.expand_albums,
+        settings.ignore_bots,
+        settings.digest_channel_id.as_deref(),
+        now.as_str(),
+        now.as_str(),
+    ))?;
+
+    Ok(())
+}
+
+pub fn get_channel_rules_by_guild_id(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+) -> Result<Vec<ChannelRule>> {
+    let c = wait_for_connection(conn)?;
+
+    let mut q = c.prepare(
+        "SELECT discord_guild_id, channel_id, allow FROM guild_channel_rules WHERE discord_guild_id = ?",
+    )?;
+
+    let r = q
+        .query_map([guild_id], |r| -> rusqlite::Result<ChannelRule> {
+            Ok(ChannelRule {
+                discord_guild_id: r.get(0)?,
+                channel_id: r.get(1)?,
+                allow: r.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<ChannelRule>>>()?;
+
+    Ok(r)
+}
+
+pub fn upsert_channel_rule(conn: &Arc<Mutex<Connection>>, rule: &ChannelRule) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare(
+        "INSERT INTO guild_channel_rules (discord_guild_id, channel_id, allow, created_at, updated_at) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT (discord_guild_id, channel_id) DO UPDATE SET
+            allow = excluded.allow,
+            updated_at = excluded.updated_at",
+    )?;
+
+    let now = Utc::now().to_string();
+    q.execute((
+        rule.discord_guild_id.as_str(),
+        rule.channel_id.as_str(),
+        rule.allow,
+        now.as_str(),
+        now.as_str(),
+    ))?;
+
+    Ok(())
+}
+
+/// Removes the rule for a channel, returning whether there was one.
+pub fn delete_channel_rule(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    channel_id: &str,
+) -> Result<bool> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let r = c.execute(
+        "DELETE FROM guild_channel_rules WHERE discord_guild_id = ? AND channel_id = ?",
+        (guild_id, channel_id),
+    )?;
+
+    Ok(r > 0)
+}
+
+/// Whether the author's posts in the guild must not be archived, either because they
+/// opted out there or everywhere.
+pub fn is_opted_out(
+    conn: &Arc<Mutex<Connection>>,
+    discord_user_id: &str,
+    guild_id: &str,
+) -> Result<bool> {
+    let c = wait_for_connection(conn)?;
+
+    let mut q = c.prepare("SELECT COUNT(*) FROM privacy_opt_outs WHERE discord_user_id = ? AND discord_guild_id IN (?, '')")?;
+
+    let r = q.query_row((discord_user_id, guild_id), |r| -> rusqlite::Result<i64> {
+        r.get(0)
+    })?;
+
+    Ok(r > 0)
+}
+
+/// The guilds the user opted out in, with `None` standing for everywhere.
+pub fn get_privacy_opt_outs_by_discord_user_id(
+    conn: &Arc<Mutex<Connection>>,
+    discord_user_id: &str,
+) -> Result<Vec<Option<String>>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q =
+        c.prepare("SELECT discord_guild_id FROM privacy_opt_outs WHERE discord_user_id = ?")?;
+
+    let r = q
+        .query_map([discord_user_id], |r| -> rusqlite::Result<String> {
+            r.get(0)
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(r.into_iter()
+        .map(|g| if g.is_empty() { None } else { Some(g) })
+        .collect())
+}
+
+pub fn create_privacy_opt_out(
+    conn: &Arc<Mutex<Connection>>,
+    discord_user_id: &str,
+    guild_id: Option<&str>,
+) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    c.execute(
+        "INSERT OR IGNORE INTO privacy_opt_outs (discord_user_id, discord_guild_id, created_at) VALUES (?, ?, ?)",
+        (
+            discord_user_id,
+            guild_id.unwrap_or_default(),
+            Utc::now().to_string(),
+        ),
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_privacy_opt_out(
+    conn: &Arc<Mutex<Connection>>,
+    discord_user_id: &str,
+    guild_id: Option<&str>,
+) -> Result<bool> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let r = c.execute(
+        "DELETE FROM privacy_opt_outs WHERE discord_user_id = ? AND discord_guild_id = ?",
+        (discord_user_id, guild_id.unwrap_or_default()),
+    )?;
+
+    Ok(r > 0)
+}
+
+pub fn create_message_link(conn: &Arc<Mutex<Connection>>, link: &MessageLink) -> Result<()> {
+    let c = wait_for_connection(conn)?;
+
+    let now = Utc::now().to_string();
+    c.execute(
+        "INSERT INTO message_links (link, message_id, guild_id, channel_id, author_discord_user_id, service, link_type, title, artist, album, genres, track_count, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        rusqlite::params![
+            link.link.as_str(),
+            link.message_id.as_str(),
+            link.guild_id.as_str(),
+            link.channel_id.as_str(),
+            link.author_discord_user_id.as_str(),
+            link.service.as_str(),
+            link.link_type.as_str(),
+            link.title.as_deref(),
+            link.artist.as_deref(),
+            link.album.as_deref(),
+            link.genres.join(","),
+            link.track_count,
+            now.as_str(),
+            now.as_str(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+// Links in the guild posted since ?2, leaving out anyone who has opted out of archiving
+// there, so their posts stop showing up in statistics too.
+const ARCHIVED_IN_GUILD: &str = "FROM message_links WHERE guild_id = ?1 AND created_at >= ?2 AND deleted_at IS NULL AND author_discord_user_id NOT IN (SELECT discord_user_id FROM privacy_opt_outs WHERE discord_guild_id IN (?1, ''))";
+
+/// Counts the guild's archived links since `since` grouped by `column`, largest first
+/// by `rank`. `column` must be one of the `message_links` columns, never user input.
+pub fn count_message_links_by(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    since: &str,
+    column: &str,
+    rank: RankBy,
+    limit: usize,
+) -> Result<Vec<ArchiveCount>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let order = rank.sql();
+
+    let mut q = c.prepare(
+        format!("SELECT {column}, COUNT(*), SUM(COALESCE(track_count, 1)) {ARCHIVED_IN_GUILD} AND {column} IS NOT NULL GROUP BY {column} ORDER BY {order} DESC LIMIT ?3")
+            .as_str(),
+    )?;
+
+    let r = q
+        .query_map(
+            (guild_id, since, limit),
+            |r| -> rusqlite::Result<ArchiveCount> {
+                Ok(ArchiveCount {
+                    key: r.get(0)?,
+                    links: r.get(1)?,
+                    tracks: r.get(2)?,
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<ArchiveCount>>>()?;
+
+    Ok(r)
+}
+
+/// Counts the guild's archived links per month, most recent first.
+pub fn count_message_links_by_month(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    limit: usize,
+) -> Result<Vec<ArchiveCount>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    // created_at starts with the date, e.g. "2025-01-31 12:00:00 UTC".
+    let mut q = c.prepare(
+        format!("SELECT substr(created_at, 1, 7) AS month, COUNT(*), SUM(COALESCE(track_count, 1)) {ARCHIVED_IN_GUILD} GROUP BY month ORDER BY month DESC LIMIT ?3")
+            .as_str(),
+    )?;
+
+    let r = q
+        .query_map(
+            (guild_id, "", limit),
+            |r| -> rusqlite::Result<ArchiveCount> {
+                Ok(ArchiveCount {
+                    key: r.get(0)?,
+                    links: r.get(1)?,
+                    tracks: r.get(2)?,
+                })
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<ArchiveCount>>>()?;
+
+    Ok(r)
+}
+
+/// The genres of each of the guild's archived links since `since` that has any.
+pub fn get_message_link_genres(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    since: &str,
+) -> Result<Vec<Vec<String>>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare(
+        format!("SELECT genres {ARCHIVED_IN_GUILD} AND genres IS NOT NULL AND genres != ''")
+            .as_str(),
+    )?;
+
+    let r = q
+        .query_map((guild_id, since), |r| -> rusqlite::Result<String> {
+            r.get(0)
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(r.iter()
+        .map(|g| g.split(',').map(String::from).collect())
+        .collect())
+}
+
+/// Artists first posted in the guild since `since`.
+pub fn get_new_artists(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    since: &str,
+    limit: usize,
+) -> Result<Vec<String>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    // Every post is looked at, so an artist posted before `since` isn't new.
+    let mut q = c.prepare(
+        format!("SELECT artist {ARCHIVED_IN_GUILD} AND artist IS NOT NULL GROUP BY artist HAVING MIN(created_at) >= ?3 ORDER BY COUNT(*) DESC LIMIT ?4")
+            .as_str(),
+    )?;
+
+    let r = q
+        .query_map(
+            (guild_id, "", since, limit),
+            |r| -> rusqlite::Result<String> { r.get(0) },
+        )?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    Ok(r)
+}
+
+pub fn get_digest_guilds(conn: &Arc<Mutex<Connection>>) -> Result<Vec<DigestGuild>> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    let mut q = c.prepare("SELECT discord_guild_id, digest_channel_id, digest_sent_at FROM guild_settings WHERE digest_channel_id IS NOT NULL")?;
+
+    let r = q
+        .query_map([], |r| -> rusqlite::Result<DigestGuild> {
+            Ok(DigestGuild {
+                discord_guild_id: r.get(0)?,
+                channel_id: r.get(1)?,
+                sent_at: r.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<DigestGuild>>>()?;
+
+    Ok(r)
+}
+
+pub fn update_guild_digest_sent_at(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    sent_at: &str,
+) -> Result<()> {
+    let Ok(c) = conn.try_lock() else {
+        return Err(DbError.into());
+    };
+
+    c.execute(
+        "UPDATE guild_settings SET digest_sent_at = ? WHERE discord_guild_id = ?",
+        (sent_at, guild_id),
+    )?;
+
+    Ok(())
+}
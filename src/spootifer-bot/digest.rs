@@ -0,0 +1,229 @@
+use crate::db::{
+    DigestGuild, RankBy, get_digest_guilds, get_guild_playlist_by_guild_id_and_service,
+    get_new_artists, get_user_by_user_id, get_user_guilds_by_guild_id_and_service,
+    update_guild_digest_sent_at,
+};
+use crate::{config, pages, stats};
+use chrono::{TimeDelta, Utc};
+use rusqlite::Connection;
+use serenity::all::{ChannelId, CreateAllowedMentions, CreateEmbed, CreateMessage, Http};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PERIOD: TimeDelta = TimeDelta::weeks(1);
+// How many albums and new artists the digest lists.
+const TOP: usize = 5;
+// Discord's limit on the length of an embed field.
+const FIELD_LIMIT: usize = 1024;
+
+const SERVICES: [&str; 3] = ["spotify", "tidal", "youtube"];
+
+/// Posts each guild's weekly digest once a week has passed since its last one.
+pub async fn run(conn: Arc<Mutex<Connection>>, http: Arc<Http>) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+
+        post_due(&conn, &http).await;
+    }
+}
+
+async fn post_due(conn: &Arc<Mutex<Connection>>, http: &Http) {
+    let guilds = match get_digest_guilds(conn) {
+        Ok(g) => g,
+        Err(e) => {
+            error!("failed to get guilds wanting a digest: {e}");
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    // Timestamps are stored in a format that sorts by time.
+    let period_start = (now - PERIOD).to_string();
+
+    for guild in guilds {
+        // The digest was turned on before first digests were scheduled a week out.
+        let Some(since) = guild.sent_at.as_deref() else {
+            start(conn, guild.discord_guild_id.as_str());
+            continue;
+        };
+
+        if since > period_start.as_str() {
+            continue;
+        }
+
+        let embed = match digest(conn, &guild, since) {
+            Ok(e) => e,
+            Err(e) => {
+                error!("failed to build digest: {e}");
+                continue;
+            }
+        };
+
+        let Ok(channel_id) = guild.channel_id.parse::<u64>() else {
+            error!("invalid digest channel id {}", guild.channel_id);
+            continue;
+        };
+
+        if let Err(e) = ChannelId::new(channel_id)
+            .send_message(
+                http,
+                CreateMessage::new()
+                    .embed(embed)
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            )
+            .await
+        {
+            error!("failed to post digest: {e}");
+            continue;
+        }
+
+        info!("posted digest to guild {}", guild.discord_guild_id);
+
+        if let Err(e) = update_guild_digest_sent_at(
+            conn,
+            guild.discord_guild_id.as_str(),
+            now.to_string().as_str(),
+        ) {
+            error!("failed to record digest: {e}");
+        }
+    }
+}
+
+/// Schedules the guild's first digest for a week from now, so that it covers a whole
+/// week.
+pub fn start(conn: &Arc<Mutex<Connection>>, guild_id: &str) {
+    if let Err(e) = update_guild_digest_sent_at(conn, guild_id, Utc::now().to_string().as_str()) {
+        error!("failed to schedule digest: {e}");
+    }
+}
+
+/// Builds the digest of what was posted in the guild since `since`.
+fn digest(conn: &Arc<Mutex<Connection>>, guild: &DigestGuild, since: &str) -> Result<CreateEmbed> {
+    let guild_id = guild.discord_guild_id.as_str();
+
+    let services = stats::counts(
+        conn,
+        guild_id,
+        since,
+        "service",
+        RankBy::Links,
+        SERVICES.len(),
+    )?;
+    let tracks: i64 = services.iter().map(|s| s.tracks).sum();
+
+    let albums = stats::counts(conn, guild_id, since, "album", RankBy::Links, TOP)?;
+
+    let new_artists = match get_new_artists(conn, guild_id, since, TOP) {
+        Ok(a) => a,
+        Err(e) => {
+            error!("failed to get new artists: {e}");
+            return Err(e.to_string().into());
+        }
+    };
+
+    // Timestamps start with the date.
+    let from = since.get(..10).unwrap_or(since);
+
+    Ok(CreateEmbed::new()
+        .title(format!("In the archive since {from}"))
+        .description(format!("{tracks} tracks posted since {from}."))
+        .field(
+            "Top albums",
+            stats::or_none(stats::count_lines(&albums)),
+            false,
+        )
+        .field(
+            "First time artists",
+            stats::or_none(new_artists.join(", ")),
+            false,
+        )
+        .field(
+            "Playlists",
+            stats::or_none(truncate_lines(&playlist_lines(conn, guild_id))),
+            false,
+        ))
+}
+
+/// A line linking each of the guild's playlists: the server's own, then members'.
+fn playlist_lines(conn: &Arc<Mutex<Connection>>, guild_id: &str) -> Vec<String> {
+    let mut lines = vec![];
+
+    for service in SERVICES {
+        let name = pages::service_display_name(service);
+
+        match get_guild_playlist_by_guild_id_and_service(conn, guild_id, service) {
+            Ok(Some(p)) => lines.push(format!(
+                "[Server {name} playlist]({})",
+                playlist_url(service, p.playlist_id.as_str())
+            )),
+            Ok(None) => {}
+            Err(e) => error!("error fetching guild playlist: {e}"),
+        }
+    }
+
+    if !config::get().processing.personal_playlists {
+        return lines;
+    }
+
+    for service in SERVICES {
+        let name = pages::service_display_name(service);
+
+        let user_guilds = match get_user_guilds_by_guild_id_and_service(conn, guild_id, service) {
+            Ok(u) => u,
+            Err(e) => {
+                error!("error fetching guilds: {e}");
+                continue;
+            }
+        };
+
+        for user_guild in user_guilds {
+            let Some(playlist_id) = user_guild.playlist_id else {
+                continue;
+            };
+
+            let Ok(user) = get_user_by_user_id(conn, user_guild.user_id) else {
+                continue;
+            };
+
+            lines.push(format!(
+                "<@{}>'s [{name} playlist]({})",
+                user.discord_user_id,
+                playlist_url(service, playlist_id.as_str())
+            ));
+        }
+    }
+
+    lines
+}
+
+fn playlist_url(service: &str, id: &str) -> String {
+    match service {
+        "spotify" => format!("https://open.spotify.com/playlist/{id}"),
+        "tidal" => format!("https://tidal.com/browse/playlist/{id}"),
+        _ => format!("https://www.youtube.com/playlist?list={id}"),
+    }
+}
+
+// Keeps as many whole lines as fit in an embed field.
+fn truncate_lines(lines: &[String]) -> String {
+    let mut value = String::new();
+
+    for line in lines {
+        if value.len() + line.len() + 1 > FIELD_LIMIT {
+            break;
+        }
+
+        if !value.is_empty() {
+            value.push('\n');
+        }
+
+        value.push_str(line);
+    }
+
+    value
+}
@@ -1,44 +1,68 @@
+use crate::cards::{self, Card};
+use crate::clients::AppClients;
 use crate::db::{
-    create_auth_request, first_or_create_user_by_discord_user_id,
-    first_or_create_user_guild_by_user_id_and_guild_id, get_oauth_token_by_user_id_and_service,
-    get_user_by_discord_user_id, get_user_by_user_id, get_user_guilds_by_guild_id_and_service,
-    update_user_guild_playlist_id,
-};
-use crate::spotify::{
-    IdType, get_album_images, get_track_ids, init_spotify, init_spotify_from_token,
+    AuthRequest, ChannelRule, GuildSettings, MatchOverride, MessageLink, UserGuild,
+    create_auth_request, create_message_link, create_privacy_opt_out, delete_channel_rule,
+    delete_privacy_opt_out, first_or_create_user_by_discord_user_id,
+    first_or_create_user_guild_by_user_id_and_guild_id, get_guild_playlist_by_guild_id_and_service,
+    get_oauth_token_by_user_id_and_service, get_privacy_opt_outs_by_discord_user_id,
+    get_user_by_discord_user_id, get_user_by_user_id,
+    get_user_guild_by_user_id_and_guild_id_and_service, get_user_guilds_by_guild_id_and_service,
+    is_opted_out, update_guild_playlist_owner, update_user_guild_playlist_id,
+    update_user_opt_in_only, upsert_channel_rule, upsert_guild_playlist, upsert_match_override,
 };
+use crate::matching::{self, MatchFailure, ResourceKey};
+use crate::settings::{self, Settings};
+use crate::spotify::{IdType, get_track_ids, init_spotify, init_spotify_from_token};
+use crate::stats;
 use crate::tidal::{TidalResource, init_tidal};
 use crate::youtube::{self, YoutubeResource, init_youtube};
-use crate::{spotify, tidal};
-use async_std::task;
+use crate::{config, digest, limits, metrics, pages, ratelimit, spotify, tidal};
 use chrono::{DateTime, TimeDelta};
+use futures::StreamExt;
+use futures::stream;
 use isopod::apis::Api as IsopodApi;
+use isopod::client::YoutubeClient;
 use isopod::models::{PlaylistItem, PlaylistItemSnippet, ResourceId};
-use log::{error, info};
 use prawn::apis::Api as PrawnApi;
 use prawn::client::{TidalClient, Token};
 use prawn::models::{
     self, PlaylistItemsRelationshipAddOperationPayload,
     PlaylistItemsRelationshipAddOperationPayloadData,
 };
-use rspotify::model::PlaylistId;
+use rspotify::AuthCodeSpotify;
+use rspotify::model::{PlayableId, PlaylistId};
 use rspotify::prelude::*;
-use rspotify::{ClientCredsSpotify, scopes};
+use rspotify::scopes;
 use rusqlite::Connection;
-use serenity::all::Message;
-use serenity::all::ReactionType::Unicode;
+use serenity::all::{
+    Builder, Channel, ChannelId, ComponentInteractionCollector, ComponentInteractionDataKind,
+    CreateActionRow, CreateAllowedMentions, CreateInteractionResponse,
+    CreateInteractionResponseMessage, CreateMessage, CreateSelectMenu, CreateSelectMenuKind,
+    CreateSelectMenuOption, EditInteractionResponse, GuildChannel, Http, Mentionable, Message,
+    MessageFlags, ReactionType, UserId,
+};
 use serenity::async_trait;
 use serenity::prelude::*;
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, instrument, warn};
 use uuid::Uuid;
 
+// Results shown from each service by /search, and how long the user has to pick one.
+const SEARCH_RESULTS: u32 = 5;
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(120);
+// Discord's limit on the label and description of a select menu option.
+const SELECT_TEXT_LIMIT: usize = 100;
+
 #[derive(Clone)]
 pub struct Handler {
     pub(crate) conn: Arc<Mutex<Connection>>,
-    pub(crate) spotify_client: Arc<ClientCredsSpotify>,
-    pub(crate) tidal_client: Arc<TidalClient>,
+    pub(crate) clients: Arc<AppClients>,
+    pub(crate) in_flight: TaskTracker,
 }
 
 struct DiscordError;
@@ -68,80 +92,503 @@ pub enum ServiceResources {
     Spotify(Vec<IdType>),
     Tidal(Vec<TidalResource>),
     Youtube(Vec<YoutubeResource>),
+    Unmatched(Vec<MatchFailure>),
+    Cards(Vec<Card>),
+    Posted(Vec<PostedLink>),
+}
+
+/// A link as it was posted, kept whether or not anything more could be found out about
+/// it, so `/stats` counts every post.
+#[derive(Clone)]
+pub struct PostedLink {
+    pub service: &'static str,
+    // "album", "track" or "video".
+    pub kind: &'static str,
+    pub link: String,
+}
+
+impl PostedLink {
+    pub fn spotify(id: &IdType) -> Self {
+        Self {
+            service: "spotify",
+            kind: match id {
+                IdType::Album(_) => "album",
+                IdType::Track(_) => "track",
+            },
+            link: cards::spotify_url(id),
+        }
+    }
+
+    pub fn tidal(resource: &TidalResource) -> Self {
+        Self {
+            service: "tidal",
+            kind: match resource {
+                TidalResource::Album(_) => "album",
+                TidalResource::Track(_) => "track",
+            },
+            link: cards::tidal_url(resource),
+        }
+    }
+
+    pub fn youtube(resource: &YoutubeResource) -> Self {
+        let YoutubeResource::Video(id) = resource;
+
+        Self {
+            service: "youtube",
+            kind: "video",
+            link: cards::youtube_url(id),
+        }
+    }
+}
+
+/// Which of a message's links are looked up and matched on the other services, and
+/// whether Tidal cover art, which only the cards show, is fetched. Everything else a
+/// card is built from is also recorded for `/stats`, so it's always looked up.
+#[derive(Clone, Copy)]
+pub struct Lookup {
+    pub albums: bool,
+    pub cover_art: bool,
+}
+
+impl Lookup {
+    /// Everything in the message, whatever the guild's settings.
+    pub const ALL: Self = Self {
+        albums: true,
+        cover_art: true,
+    };
+
+    /// Album links are skipped altogether when the guild doesn't expand them, so they
+    /// aren't matched on other services only to be thrown away. Cover art isn't
+    /// fetched when the guild doesn't want cards.
+    pub fn for_settings(settings: &Settings) -> Self {
+        Self {
+            albums: settings.expand_albums(),
+            cover_art: settings.album_art(),
+        }
+    }
+}
+
+/// How many tracks or videos one set of a message's links added to playlists.
+pub struct Added {
+    pub service: &'static str,
+    pub items: usize,
+}
+
+/// Whose playlists the links in a message are added to.
+#[derive(Clone, Copy)]
+pub enum Recipients {
+    // Everyone in the guild with a registered playlist who isn't in opt-in only mode.
+    Automatic,
+    // Just this user, who picked the message with "Add to my playlists".
+    User(i64),
+    // This user's own playlist and the server's, if they own it, for `/fix_match`.
+    Owner(i64),
 }
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, new_message: Message) {
-        let content = new_message.content.clone();
+        if self.in_flight.is_closed() {
+            info!("shutting down, ignoring message");
+            return;
+        }
 
-        let resources = [
-            spotify::extract_resources(
-                self.spotify_client.as_ref(),
-                self.tidal_client.as_ref(),
-                content.as_str(),
-            )
-            .await,
-            tidal::extract_resources(
-                self.tidal_client.as_ref(),
-                self.spotify_client.as_ref(),
-                content.as_str(),
-            )
-            .await,
-            youtube::extract_resources(content.as_str()),
-        ]
-        .concat();
+        self.in_flight
+            .track_future(self.handle_message(ctx, new_message, Recipients::Automatic))
+            .await;
+    }
+}
+
+impl Handler {
+    #[instrument(
+        name = "message",
+        skip_all,
+        fields(
+            message_id = %new_message.id,
+            channel_id = %new_message.channel_id,
+            guild_id = new_message.guild_id.map(|g| g.get()),
+            author_id = %new_message.author.id,
+        )
+    )]
+    async fn handle_message(
+        &self,
+        ctx: Context,
+        new_message: Message,
+        recipients: Recipients,
+    ) -> Vec<Added> {
+        metrics::record_message_processed();
+
+        // Playlists and settings are per guild, so there is nothing to do with DMs.
+        let Some(guild_id) = new_message.guild_id else {
+            info!("ignoring message outside of a guild");
+            return vec![];
+        };
+
+        let settings = &settings::for_guild(&self.conn, guild_id.to_string().as_str());
+
+        if self.author_opted_out(&new_message, guild_id.to_string().as_str()) {
+            info!("author opted out of archiving, ignoring message");
+            return vec![];
+        }
+
+        // Messages picked with "Add to my playlists" skip the ingestion rules.
+        if let Recipients::Automatic = recipients {
+            // Links the bot posts for /search are handled as they're sent.
+            if new_message.author.id == ctx.cache.current_user().id {
+                return vec![];
+            }
+
+            if settings.ignore_bots()
+                && (new_message.author.bot || new_message.webhook_id.is_some())
+            {
+                info!("ignoring message from a bot or webhook");
+                return vec![];
+            }
+
+            if settings.has_channel_rules()
+                && !settings.watches(&channel_lineage(&ctx, new_message.channel_id).await)
+            {
+                info!("ignoring message in an unwatched channel");
+                return vec![];
+            }
+        }
+
+        let resources = self
+            .extract_resources(new_message.content.as_str(), Lookup::for_settings(settings))
+            .await;
+
+        if let Recipients::Automatic = recipients {
+            self.record_links(&new_message, guild_id.to_string().as_str(), &resources);
+        }
 
         info!("processing {} resource sets", resources.len());
 
-        for resource_set in resources.clone() {
+        // Each service makes its own cards, but the message only gets one reply.
+        let mut cards = vec![];
+        let mut link_sets = vec![];
+
+        for resource_set in resources {
             match resource_set {
-                ServiceResources::Spotify(spotify_ids) => {
-                    self.clone()
-                        .handle_spotify_links(&ctx, new_message.clone(), spotify_ids.clone())
-                        .await;
+                ServiceResources::Cards(c) => cards.extend(c),
+                other => link_sets.push(other),
+            }
+        }
+
+        let ctx = &ctx;
+        let new_message = &new_message;
+
+        let handled =
+            futures::future::join_all(link_sets.into_iter().map(|resource_set| async move {
+                match resource_set {
+                    ServiceResources::Spotify(spotify_ids) => Some(Added {
+                        service: "spotify",
+                        items: self
+                            .handle_spotify_links(
+                                ctx,
+                                new_message,
+                                spotify_ids,
+                                settings,
+                                recipients,
+                            )
+                            .await,
+                    }),
+                    ServiceResources::Tidal(tidal_ids) => Some(Added {
+                        service: "tidal",
+                        items: self
+                            .handle_tidal_links(ctx, new_message, tidal_ids, settings, recipients)
+                            .await,
+                    }),
+                    ServiceResources::Youtube(youtube_ids) => Some(Added {
+                        service: "youtube",
+                        items: self
+                            .handle_youtube_links(
+                                ctx,
+                                new_message,
+                                youtube_ids,
+                                settings,
+                                recipients,
+                            )
+                            .await,
+                    }),
+                    ServiceResources::Cards(_) | ServiceResources::Posted(_) => None,
+                    ServiceResources::Unmatched(failures) => {
+                        // The automatic pass already explained these to the channel.
+                        if let Recipients::Automatic = recipients {
+                            self.handle_unmatched(ctx, new_message, failures).await;
+                        }
+                        None
+                    }
+                }
+            }));
+
+        let (added, ()) = tokio::join!(
+            handled,
+            self.handle_cards(ctx, new_message, cards, settings, recipients)
+        );
+
+        added.into_iter().flatten().collect()
+    }
+
+    /// Finds the links in `content` and matches them on the other enabled services.
+    async fn extract_resources(&self, content: &str, lookup: Lookup) -> Vec<ServiceResources> {
+        let spotify_client = self.clients.spotify();
+        let tidal_client = self.clients.tidal();
+
+        let (spotify_resources, tidal_resources, youtube_resources) = tokio::join!(
+            async {
+                match &spotify_client {
+                    Some(s) => {
+                        spotify::extract_resources(
+                            &self.conn,
+                            s.as_ref(),
+                            tidal_client.as_deref(),
+                            content,
+                            lookup,
+                        )
+                        .await
+                    }
+                    None => vec![],
+                }
+            },
+            async {
+                match &tidal_client {
+                    Some(t) => {
+                        tidal::extract_resources(
+                            &self.conn,
+                            t.as_ref(),
+                            spotify_client.as_deref(),
+                            content,
+                            lookup,
+                        )
+                        .await
+                    }
+                    None => vec![],
                 }
-                ServiceResources::Tidal(tidal_ids) => {
-                    self.clone()
-                        .handle_tidal_links(&ctx, new_message.clone(), tidal_ids.clone())
-                        .await;
+            },
+            async {
+                if config::get().youtube.is_some() {
+                    youtube::extract_resources(content).await
+                } else {
+                    vec![]
                 }
-                ServiceResources::Youtube(youtube_ids) => {
-                    self.clone()
-                        .handle_youtube_links(&ctx, new_message.clone(), youtube_ids.clone())
-                        .await;
+            },
+        );
+
+        [spotify_resources, tidal_resources, youtube_resources].concat()
+    }
+
+    /// Keeps a record of each album, track or video posted in the message for `/stats`,
+    /// with whatever details its card has, if one could be made.
+    fn record_links(&self, message: &Message, guild_id: &str, resources: &[ServiceResources]) {
+        let cards: Vec<&Card> = resources
+            .iter()
+            .filter_map(|r| match r {
+                ServiceResources::Cards(c) => Some(c),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+
+        for resource_set in resources {
+            let ServiceResources::Posted(posted) = resource_set else {
+                continue;
+            };
+
+            for posted_link in posted {
+                let card = cards
+                    .iter()
+                    .find(|c| c.links.iter().any(|(_, l)| *l == posted_link.link));
+
+                let record = MessageLink {
+                    link: posted_link.link.clone(),
+                    message_id: message.id.to_string(),
+                    guild_id: guild_id.to_string(),
+                    channel_id: message.channel_id.to_string(),
+                    author_discord_user_id: message.author.id.to_string(),
+                    service: posted_link.service.to_string(),
+                    link_type: posted_link.kind.to_string(),
+                    title: card.map(|c| c.title.clone()),
+                    artist: card.and_then(|c| c.artists.first().cloned()),
+                    album: card.and_then(|c| c.album.clone()),
+                    genres: card.map(|c| c.genres.clone()).unwrap_or_default(),
+                    track_count: card.and_then(|c| c.track_count),
+                };
+
+                if let Err(e) = create_message_link(&self.conn, &record) {
+                    error!("failed to record message link: {e}");
                 }
             }
         }
     }
-}
 
-impl Handler {
+    /// Whether the message's author asked for their posts not to be archived here. If
+    /// that can't be checked the message is skipped, to be safe.
+    fn author_opted_out(&self, message: &Message, guild_id: &str) -> bool {
+        match is_opted_out(&self.conn, message.author.id.to_string().as_str(), guild_id) {
+            Ok(o) => o,
+            Err(e) => {
+                error!("failed to check privacy opt out: {e}");
+                true
+            }
+        }
+    }
+
+    /// The playlists in the guild that links for `service` go to: the server's own
+    /// playlist and, if enabled, users' personal ones. Each is returned as the
+    /// `UserGuild` of whoever's token writes to it.
+    fn playlist_guilds(
+        &self,
+        guild_id: &str,
+        service: &str,
+        recipients: Recipients,
+    ) -> Option<Vec<UserGuild>> {
+        let mut user_guilds = if config::get().processing.personal_playlists {
+            match get_user_guilds_by_guild_id_and_service(&self.conn, guild_id, service) {
+                Ok(u) => u,
+                Err(e) => {
+                    error!("error fetching guilds: {e}");
+                    return None;
+                }
+            }
+        } else {
+            vec![]
+        };
+
+        user_guilds.retain(|g| match recipients {
+            Recipients::Automatic => {
+                get_user_by_user_id(&self.conn, g.user_id).is_ok_and(|u| !u.opt_in_only)
+            }
+            Recipients::User(user_id) | Recipients::Owner(user_id) => g.user_id == user_id,
+        });
+
+        // The server's playlist only follows the channel, never a single user's picks.
+        if let Recipients::User(_) = recipients {
+            return Some(user_guilds);
+        }
+
+        match get_guild_playlist_by_guild_id_and_service(&self.conn, guild_id, service) {
+            Ok(Some(p)) if recipients_include_owner(recipients, p.owner_user_id) => {
+                user_guilds.retain(|g| g.playlist_id.as_deref() != Some(p.playlist_id.as_str()));
+                user_guilds.push(UserGuild {
+                    user_id: p.owner_user_id,
+                    discord_guild_id: p.discord_guild_id,
+                    playlist_id: Some(p.playlist_id),
+                    deleted_at: None,
+                    created_at: p.created_at,
+                    updated_at: p.updated_at,
+                    for_service: p.for_service,
+                });
+            }
+            Ok(_) => {}
+            Err(e) => error!("error fetching guild playlist: {e}"),
+        }
+
+        Some(user_guilds)
+    }
+
+    /// Replies to the message with a card for each album or track in it.
+    #[instrument(skip_all, fields(cards = cards.len()))]
+    async fn handle_cards(
+        &self,
+        ctx: &Context,
+        new_message: &Message,
+        cards: Vec<Card>,
+        settings: &Settings,
+        recipients: Recipients,
+    ) {
+        // Cards were already posted when the message first came in.
+        if let Recipients::User(_) = recipients {
+            return;
+        }
+
+        if cards.is_empty() || !settings.album_art() {
+            return;
+        }
+
+        match new_message
+            .channel_id
+            .send_message(&ctx.http, cards::reply(new_message, &cards))
+            .await
+        {
+            Ok(_) => info!("sent cards to channel"),
+            Err(e) => error!("failed to send cards: {e}"),
+        }
+    }
+
+    /// Replies to the message explaining why links could not be matched, but only for
+    /// services someone in the guild actually has a playlist registered for.
+    #[instrument(skip_all, fields(failures = failures.len()))]
+    async fn handle_unmatched(
+        &self,
+        ctx: &Context,
+        new_message: &Message,
+        failures: Vec<MatchFailure>,
+    ) {
+        let Some(guild_id) = new_message.guild_id else {
+            return;
+        };
+
+        let relevant: Vec<MatchFailure> = failures
+            .into_iter()
+            .filter(|f| {
+                self.playlist_guilds(guild_id.to_string().as_str(), f.to, Recipients::Automatic)
+                    .is_some_and(|g| !g.is_empty())
+            })
+            .collect();
+
+        if relevant.is_empty() {
+            return;
+        }
+
+        let lines = relevant
+            .iter()
+            .map(|f| format!("- {f}"))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let content = format!(
+            "Couldn't find a match for:\n{lines}\nIf you know the right link, a server admin can set it with `/fix_match`."
+        );
+
+        if let Err(e) = new_message
+            .channel_id
+            .send_message(
+                &ctx.http,
+                CreateMessage::new()
+                    .content(content)
+                    .reference_message(new_message)
+                    .allowed_mentions(CreateAllowedMentions::new().replied_user(false)),
+            )
+            .await
+        {
+            error!("failed to reply with match failures: {e}");
+        }
+    }
+
     #[allow(clippy::too_many_lines)]
+    #[instrument(skip_all, fields(service = "youtube", resources = youtube_ids.len()))]
     async fn handle_youtube_links(
         &self,
         ctx: &serenity::all::Context,
-        new_message: Message,
+        new_message: &Message,
         youtube_ids: Vec<YoutubeResource>,
-    ) {
+        settings: &Settings,
+        recipients: Recipients,
+    ) -> usize {
         let Some(guild_id) = new_message.guild_id else {
             error!("message not in a guild");
-            return;
+            return 0;
         };
 
-        let user_guilds = match get_user_guilds_by_guild_id_and_service(
-            &self.conn,
-            guild_id.to_string().as_str(),
-            "youtube",
-        ) {
-            Ok(u) => u,
-            Err(e) => {
-                error!("error fetching guilds: {e}");
-                return;
-            }
+        let Some(user_guilds) =
+            self.playlist_guilds(guild_id.to_string().as_str(), "youtube", recipients)
+        else {
+            return 0;
         };
 
-        for guild in user_guilds.clone() {
+        let mut targets = vec![];
+
+        for guild in user_guilds {
             let user = match get_user_by_user_id(&self.conn, guild.user_id) {
                 Ok(u) => u,
                 Err(e) => {
@@ -184,100 +631,142 @@ impl Handler {
                 continue;
             };
 
-            for YoutubeResource::Video(id) in youtube_ids.clone() {
-                match youtube_client
-                    .playlist_items_api()
-                    .youtube_playlist_items_insert(
-                        vec!["snippet".to_string()],
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        None,
-                        Some(PlaylistItem {
-                            snippet: Some(Box::new(PlaylistItemSnippet {
-                                playlist_id: Some(p.clone()),
-                                resource_id: Some(Box::new(ResourceId {
-                                    kind: Some("youtube#video".to_string()),
-                                    video_id: Some(id),
-                                    ..Default::default()
-                                })),
-                                ..Default::default()
-                            })),
-                            ..Default::default()
-                        }),
-                    )
-                    .await
-                {
-                    Ok(_) => {
-                        info!("added youtube link to playlist");
-                    }
-                    Err(e) => {
-                        error!(
-                            "failed to add youtube item to playlist {}: {}",
-                            p.clone(),
-                            e
-                        );
-                    }
-                }
-            }
+            targets.push((youtube_client, p, user_id));
         }
-        if !user_guilds.is_empty() {
-            let mills500 = std::time::Duration::from_millis(500);
-            task::sleep(mills500).await;
+
+        let added = stream::iter(targets)
+            .map(|(client, p, user_id)| add_youtube_items(client, p, user_id, &youtube_ids))
+            .buffer_unordered(limits::max_concurrent_writes())
+            .fold(0, |total, n| async move { total + n })
+            .await;
+
+        if added > 0 {
             info!("acknowledging message");
-            _ = new_message.react(&ctx, Unicode(String::from("🏮"))).await;
+            if let Err(e) = new_message.react(&ctx, settings.reaction("youtube")).await {
+                error!("failed to react to message: {e}");
+            }
         }
+
+        added
     }
     #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
+    #[instrument(skip_all, fields(service = "tidal", resources = tidal_ids.len()))]
     async fn handle_tidal_links(
         &self,
         ctx: &serenity::all::Context,
-        new_message: Message,
+        new_message: &Message,
         tidal_ids: Vec<TidalResource>,
-    ) {
+        settings: &Settings,
+        recipients: Recipients,
+    ) -> usize {
         let Some(guild_id) = new_message.guild_id else {
             error!("message not in a guild");
-            return;
+            return 0;
         };
 
-        let user_guilds = match get_user_guilds_by_guild_id_and_service(
-            &self.conn,
-            guild_id.to_string().as_str(),
-            "tidal",
-        ) {
-            Ok(u) => u,
-            Err(e) => {
-                error!("error fetching guilds: {e}");
-                return;
-            }
+        let Some(user_guilds) =
+            self.playlist_guilds(guild_id.to_string().as_str(), "tidal", recipients)
+        else {
+            return 0;
+        };
+
+        let Some(app_tidal_client) = self.clients.tidal() else {
+            warn!("tidal is disabled, skipping tidal links");
+            return 0;
         };
 
-        let track_ids = match tidal::get_track_ids(&self.tidal_client, &tidal_ids).await {
+        let track_ids = match tidal::get_track_ids(&app_tidal_client, &tidal_ids).await {
             Ok(t) => t,
             Err(e) => {
                 error!("error fetching track ids: {e}");
-                return;
+                return 0;
             }
         };
 
-        let track_ids_payload_data: Vec<PlaylistItemsRelationshipAddOperationPayloadData> = track_ids.clone().into_iter().map(|s: String| -> PlaylistItemsRelationshipAddOperationPayloadData {
-                       PlaylistItemsRelationshipAddOperationPayloadData { id: s, meta: None, r#type: models::playlist_items_relationship_add_operation_payload_data::Type::Tracks }
-                    }).collect();
+        if track_ids.is_empty() {
+            return 0;
+        }
+
+        let track_ids_payload_data = tidal_payload_data(track_ids);
 
         info!("{} tracks to add", track_ids_payload_data.len());
 
         let chunked_data: Vec<&[PlaylistItemsRelationshipAddOperationPayloadData]> =
             track_ids_payload_data.chunks(20).collect();
 
-        for guild in user_guilds.clone() {
+        let targets = self.tidal_targets(&user_guilds);
+
+        let added = stream::iter(targets)
+            .map(|(client, p, user_id)| add_tidal_items(client, p, user_id, &chunked_data))
+            .buffer_unordered(limits::max_concurrent_writes())
+            .fold(0, |total, n| async move { total + n })
+            .await;
+
+        if added > 0 {
+            info!("acknowledging message");
+            if let Err(e) = new_message.react(&ctx, settings.reaction("tidal")).await {
+                error!("failed to react to message: {e}");
+            }
+        }
+
+        added
+    }
+
+    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
+    #[instrument(skip_all, fields(service = "spotify", resources = spotify_ids.len()))]
+    async fn handle_spotify_links(
+        &self,
+        ctx: &serenity::all::Context,
+        new_message: &Message,
+        spotify_ids: Vec<IdType>,
+        settings: &Settings,
+        recipients: Recipients,
+    ) -> usize {
+        let Some(guild_id) = new_message.guild_id else {
+            error!("message not in a guild");
+            return 0;
+        };
+
+        let Some(user_guilds) =
+            self.playlist_guilds(guild_id.to_string().as_str(), "spotify", recipients)
+        else {
+            return 0;
+        };
+
+        let Some(app_spotify_client) = self.clients.spotify() else {
+            warn!("spotify is disabled, skipping spotify links");
+            return 0;
+        };
+
+        let track_ids = get_track_ids(&app_spotify_client, &spotify_ids).await;
+
+        if track_ids.is_empty() {
+            return 0;
+        }
+
+        let targets = self.spotify_targets(&user_guilds);
+
+        let added = stream::iter(targets)
+            .map(|(client, p, user_id)| add_spotify_items(client, p, user_id, &track_ids))
+            .buffer_unordered(limits::max_concurrent_writes())
+            .fold(0, |total, n| async move { total + n })
+            .await;
+
+        if added > 0 {
+            info!("acknowledging message");
+            if let Err(e) = new_message.react(&ctx, settings.reaction("spotify")).await {
+                error!("failed to react to message: {e}");
+            }
+        }
+
+        added
+    }
+
+    /// Builds a client for every user in `user_guilds` with a registered Tidal playlist.
+    fn tidal_targets(&self, user_guilds: &[UserGuild]) -> Vec<(TidalClient, String, i64)> {
+        let mut targets = vec![];
+
+        for guild in user_guilds {
             let user = match get_user_by_user_id(&self.conn, guild.user_id) {
                 Ok(u) => u,
                 Err(e) => {
@@ -315,70 +804,22 @@ impl Handler {
                 }
             };
 
-            let Some(p) = guild.playlist_id else {
+            let Some(p) = guild.playlist_id.clone() else {
                 error!("playlist id not present");
                 continue;
             };
 
-            for data in chunked_data.clone() {
-                info!("attempting to add chunk");
-                match tidal_client
-                    .playlists_api()
-                    .add_items_to_playlist(
-                        p.as_str(),
-                        None,
-                        Some(PlaylistItemsRelationshipAddOperationPayload {
-                            data: data.to_vec(),
-                            meta: None,
-                        }),
-                    )
-                    .await
-                {
-                    Ok(()) => {
-                        info!("added {} items to playlist", data.len());
-                    }
-                    Err(e) => {
-                        error!("failed to add tracks to playlist: {e}");
-                    }
-                }
-            }
+            targets.push((tidal_client, p, user_id));
         }
 
-        if !user_guilds.is_empty() {
-            let mills500 = std::time::Duration::from_millis(500);
-            task::sleep(mills500).await;
-            info!("acknowledging message");
-            _ = new_message.react(&ctx, Unicode(String::from("🌊"))).await;
-        }
+        targets
     }
 
-    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
-    async fn handle_spotify_links(
-        self,
-        ctx: &serenity::all::Context,
-        new_message: Message,
-        spotify_ids: Vec<IdType>,
-    ) {
-        let Some(guild_id) = new_message.guild_id else {
-            error!("message not in a guild");
-            return;
-        };
-
-        let user_guilds = match get_user_guilds_by_guild_id_and_service(
-            &self.conn,
-            guild_id.to_string().as_str(),
-            "spotify",
-        ) {
-            Ok(u) => u,
-            Err(e) => {
-                error!("error fetching guilds: {e}");
-                return;
-            }
-        };
+    /// Builds a client for every user in `user_guilds` with a registered Spotify playlist.
+    fn spotify_targets(&self, user_guilds: &[UserGuild]) -> Vec<(AuthCodeSpotify, String, i64)> {
+        let mut targets = vec![];
 
-        let track_ids = get_track_ids(&self.spotify_client, &spotify_ids).await;
-
-        for guild in user_guilds.clone() {
+        for guild in user_guilds {
             let user = match get_user_by_user_id(&self.conn, guild.user_id) {
                 Ok(u) => u,
                 Err(e) => {
@@ -425,52 +866,999 @@ impl Handler {
                 Ok(c) => c,
                 Err(e) => {
                     error!("error getting spotify client: {e}");
-                    return;
+                    continue;
                 }
             };
 
-            let Some(p) = guild.playlist_id else {
+            let Some(p) = guild.playlist_id.clone() else {
                 error!("playlist id not present");
                 continue;
             };
 
-            let playlist_id = match PlaylistId::from_id(&p) {
-                Ok(id) => id,
-                Err(e) => {
-                    error!("Failed to get playlist id: {e}");
-                    continue;
-                }
+            targets.push((spotify_client, p, user_id));
+        }
+
+        targets
+    }
+
+    /// Swaps the tracks of `wrong` for the tracks of `right` in the user's own Spotify
+    /// playlist in the guild, and the server's if they own it, returning how many
+    /// playlists were updated. Other members' playlists are left alone, since removing a
+    /// track takes out every copy of it, including ones added by hand.
+    async fn replace_spotify_match(
+        &self,
+        guild_id: &str,
+        user_id: i64,
+        wrong: &Vec<IdType>,
+        right: IdType,
+    ) -> usize {
+        let Some(app_spotify_client) = self.clients.spotify() else {
+            return 0;
+        };
+
+        let Some(user_guilds) =
+            self.playlist_guilds(guild_id, "spotify", Recipients::Owner(user_id))
+        else {
+            return 0;
+        };
+
+        let right = vec![right];
+        let right_ids = get_track_ids(&app_spotify_client, &right).await;
+        let wrong_ids: Vec<PlayableId> = get_track_ids(&app_spotify_client, wrong)
+            .await
+            .into_iter()
+            .filter(|id| !right_ids.contains(id))
+            .collect();
+
+        let targets = self.spotify_targets(&user_guilds);
+        let updated = targets.len();
+
+        stream::iter(targets)
+            .for_each_concurrent(limits::max_concurrent_writes(), |(client, p, user_id)| {
+                replace_spotify_items(client, p, user_id, &wrong_ids, &right_ids)
+            })
+            .await;
+
+        updated
+    }
+
+    /// Adds the tracks of `right` to the user's own Tidal playlist in the guild, and the
+    /// server's if they own it, returning how many playlists were updated.
+    async fn add_tidal_match(&self, guild_id: &str, user_id: i64, right: TidalResource) -> usize {
+        let Some(app_tidal_client) = self.clients.tidal() else {
+            return 0;
+        };
+
+        let Some(user_guilds) = self.playlist_guilds(guild_id, "tidal", Recipients::Owner(user_id))
+        else {
+            return 0;
+        };
+
+        let track_ids = match tidal::get_track_ids(&app_tidal_client, &vec![right]).await {
+            Ok(t) => t,
+            Err(e) => {
+                error!("error fetching track ids: {e}");
+                return 0;
+            }
+        };
+
+        let payload_data = tidal_payload_data(track_ids);
+        let chunked_data: Vec<&[PlaylistItemsRelationshipAddOperationPayloadData]> =
+            payload_data.chunks(20).collect();
+
+        let targets = self.tidal_targets(&user_guilds);
+
+        stream::iter(targets)
+            .map(|(client, p, user_id)| add_tidal_items(client, p, user_id, &chunked_data))
+            .buffer_unordered(limits::max_concurrent_writes())
+            .fold(0, |updated, added| async move {
+                updated + usize::from(added > 0)
+            })
+            .await
+    }
+}
+
+// Whether the server's playlist, owned by `owner_user_id`, gets the links.
+const fn recipients_include_owner(recipients: Recipients, owner_user_id: i64) -> bool {
+    match recipients {
+        Recipients::Automatic => true,
+        Recipients::User(_) => false,
+        Recipients::Owner(user_id) => user_id == owner_user_id,
+    }
+}
+
+/// The channel and the channels it sits under, i.e. a thread's channel and a channel's
+/// category, so channel rules can match on any of them.
+async fn channel_lineage(ctx: &Context, channel_id: ChannelId) -> Vec<ChannelId> {
+    let mut lineage = vec![channel_id];
+    let mut current = channel_id;
+
+    for _ in 0..2 {
+        let parent = match current.to_channel(ctx).await {
+            Ok(Channel::Guild(c)) => c.parent_id,
+            Ok(_) => None,
+            Err(e) => {
+                warn!("failed to look up channel {current}: {e}");
+                None
+            }
+        };
+
+        let Some(parent) = parent else {
+            break;
+        };
+
+        lineage.push(parent);
+        current = parent;
+    }
+
+    lineage
+}
+
+#[instrument(skip_all, fields(service = "youtube", playlist_id = %p, user_id = user_id))]
+async fn add_youtube_items(
+    youtube_client: YoutubeClient,
+    p: String,
+    user_id: i64,
+    youtube_ids: &[YoutubeResource],
+) -> usize {
+    let Some(_write) = limits::acquire_write("youtube", p.as_str()).await else {
+        error!("unable to acquire youtube write");
+        return 0;
+    };
+
+    let mut added = 0;
+
+    for YoutubeResource::Video(id) in youtube_ids.iter().cloned() {
+        match ratelimit::call("youtube", "playlist_items_insert", Some(user_id), || {
+            youtube_client
+                .playlist_items_api()
+                .youtube_playlist_items_insert(
+                    vec!["snippet".to_string()],
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    Some(PlaylistItem {
+                        snippet: Some(Box::new(PlaylistItemSnippet {
+                            playlist_id: Some(p.clone()),
+                            resource_id: Some(Box::new(ResourceId {
+                                kind: Some("youtube#video".to_string()),
+                                video_id: Some(id.clone()),
+                                ..Default::default()
+                            })),
+                            ..Default::default()
+                        })),
+                        ..Default::default()
+                    }),
+                )
+        })
+        .await
+        {
+            Ok(_) => {
+                metrics::record_playlist_add("youtube", true);
+                info!("added youtube link to playlist");
+                added += 1;
+            }
+            Err(e) => {
+                metrics::record_playlist_add("youtube", false);
+                error!("failed to add youtube item to playlist {p}: {e}");
+            }
+        }
+    }
+
+    added
+}
+
+#[instrument(skip_all, fields(service = "tidal", playlist_id = %p, user_id = user_id))]
+async fn add_tidal_items(
+    tidal_client: TidalClient,
+    p: String,
+    user_id: i64,
+    chunked_data: &[&[PlaylistItemsRelationshipAddOperationPayloadData]],
+) -> usize {
+    let Some(_write) = limits::acquire_write("tidal", p.as_str()).await else {
+        error!("unable to acquire tidal write");
+        return 0;
+    };
+
+    let mut added = 0;
+
+    for data in chunked_data {
+        info!("attempting to add chunk");
+        match ratelimit::call("tidal", "add_items_to_playlist", Some(user_id), || {
+            tidal_client.playlists_api().add_items_to_playlist(
+                p.as_str(),
+                None,
+                Some(PlaylistItemsRelationshipAddOperationPayload {
+                    data: data.to_vec(),
+                    meta: None,
+                }),
+            )
+        })
+        .await
+        {
+            Ok(()) => {
+                metrics::record_playlist_add("tidal", true);
+                info!("added {} items to playlist", data.len());
+                added += data.len();
+            }
+            Err(e) => {
+                metrics::record_playlist_add("tidal", false);
+                error!("failed to add tracks to playlist: {e}");
+            }
+        }
+    }
+
+    added
+}
+
+#[instrument(skip_all, fields(service = "spotify", playlist_id = %p, user_id = user_id))]
+async fn add_spotify_items(
+    spotify_client: AuthCodeSpotify,
+    p: String,
+    user_id: i64,
+    track_ids: &[PlayableId<'_>],
+) -> usize {
+    let playlist_id = match PlaylistId::from_id(&p) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Failed to get playlist id: {e}");
+            return 0;
+        }
+    };
+
+    let Some(_write) = limits::acquire_write("spotify", p.as_str()).await else {
+        error!("unable to acquire spotify write");
+        return 0;
+    };
+
+    match ratelimit::call("spotify", "playlist_add_items", Some(user_id), || {
+        spotify_client.playlist_add_items(playlist_id.clone(), track_ids.to_vec(), None)
+    })
+    .await
+    {
+        Ok(_) => {
+            metrics::record_playlist_add("spotify", true);
+            info!("Added tracks to playlist");
+            track_ids.len()
+        }
+        Err(e) => {
+            metrics::record_playlist_add("spotify", false);
+            error!("Failed to add tracks to playlist: {e}");
+            0
+        }
+    }
+}
+
+fn tidal_payload_data(
+    track_ids: Vec<String>,
+) -> Vec<PlaylistItemsRelationshipAddOperationPayloadData> {
+    track_ids.into_iter().map(|s: String| -> PlaylistItemsRelationshipAddOperationPayloadData {
+        PlaylistItemsRelationshipAddOperationPayloadData { id: s, meta: None, r#type: models::playlist_items_relationship_add_operation_payload_data::Type::Tracks }
+    }).collect()
+}
+
+#[instrument(skip_all, fields(service = "spotify", playlist_id = %p, user_id = user_id))]
+async fn replace_spotify_items(
+    spotify_client: AuthCodeSpotify,
+    p: String,
+    user_id: i64,
+    wrong_ids: &[PlayableId<'_>],
+    right_ids: &[PlayableId<'_>],
+) {
+    if !wrong_ids.is_empty() {
+        let playlist_id = match PlaylistId::from_id(&p) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to get playlist id: {e}");
+                return;
+            }
+        };
+
+        let Some(_write) = limits::acquire_write("spotify", p.as_str()).await else {
+            error!("unable to acquire spotify write");
+            return;
+        };
+
+        match ratelimit::call(
+            "spotify",
+            "playlist_remove_all_occurrences_of_items",
+            Some(user_id),
+            || {
+                spotify_client.playlist_remove_all_occurrences_of_items(
+                    playlist_id.clone(),
+                    wrong_ids.to_vec(),
+                    None,
+                )
+            },
+        )
+        .await
+        {
+            Ok(_) => info!("removed {} wrongly matched tracks", wrong_ids.len()),
+            Err(e) => error!("Failed to remove tracks from playlist: {e}"),
+        }
+    }
+
+    add_spotify_items(spotify_client, p, user_id, right_ids).await;
+}
+
+/// The slash commands to register, leaving out authorization for disabled services and
+/// personal playlist commands when those are turned off.
+pub fn commands() -> Vec<poise::Command<Arc<Handler>, CommandError>> {
+    let config = config::get();
+
+    let mut commands = vec![
+        register_guild_playlist(),
+        transfer_guild_playlist(),
+        guild_settings(),
+        privacy(),
+        convert(),
+        stats(),
+        leaderboard(),
+    ];
+
+    if config.spotify.is_some() || config.tidal.is_some() {
+        commands.push(search());
+    }
+
+    if config.processing.personal_playlists {
+        commands.push(register_playlist());
+        commands.push(add_to_my_playlists());
+        commands.push(opt_in_only());
+    }
+
+    if config.spotify.is_some() && config.tidal.is_some() {
+        commands.push(fix_match());
+    }
+
+    if config.spotify.is_some() {
+        commands.push(authorize_spotify());
+    }
+
+    if config.tidal.is_some() {
+        commands.push(authorize_tidal());
+    }
+
+    if config.youtube.is_some() {
+        commands.push(authorize_youtube());
+    }
+
+    commands
+}
+
+#[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
+pub enum ServiceChoice {
+    #[name = "Spotify"]
+    Spotify,
+    #[name = "TIDAL"]
+    Tidal,
+    #[name = "YouTube"]
+    Youtube,
+}
+
+impl ServiceChoice {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Spotify => "spotify",
+            Self::Tidal => "tidal",
+            Self::Youtube => "youtube",
+        }
+    }
+}
+
+fn link_service(link: &str) -> Option<&'static str> {
+    if spotify::contains_spotify_link(link) {
+        Some("spotify")
+    } else if tidal::contains_tidal_link(link) {
+        Some("tidal")
+    } else if youtube::contains_youtube_link(link) {
+        Some("youtube")
+    } else {
+        None
+    }
+}
+
+fn service_enabled(service: &str) -> bool {
+    let config = config::get();
+
+    match service {
+        "spotify" => config.spotify.is_some(),
+        "tidal" => config.tidal.is_some(),
+        "youtube" => config.youtube.is_some(),
+        _ => false,
+    }
+}
+
+async fn reply_ephemeral(ctx: CommandCtx<'_>, content: String) -> Result<()> {
+    match ctx
+        .send(
+            poise::CreateReply::default()
+                .content(content)
+                .ephemeral(true),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replies with the equivalent of a link on every enabled service it could be matched
+/// on. Nothing is added to any playlists.
+#[poise::command(slash_command)]
+pub async fn convert(ctx: CommandCtx<'_>, link: String) -> Result<()> {
+    if !link_service(link.as_str()).is_some_and(service_enabled) {
+        return reply_ephemeral(
+            ctx,
+            String::from("That isn't a link to a service this bot supports."),
+        )
+        .await;
+    }
+
+    ctx.defer().await?;
+
+    let mut cards = vec![];
+    let mut failures = vec![];
+
+    for resource_set in ctx
+        .data()
+        .extract_resources(link.as_str(), Lookup::ALL)
+        .await
+    {
+        match resource_set {
+            ServiceResources::Cards(c) => cards.extend(c),
+            ServiceResources::Unmatched(f) => failures.extend(f),
+            _ => {}
+        }
+    }
+
+    if cards.is_empty() {
+        ctx.say("Couldn't find anything at that link.").await?;
+        return Ok(());
+    }
+
+    let mut reply = cards::command_reply(&cards);
+
+    if !failures.is_empty() {
+        let lines = failures
+            .iter()
+            .map(|f| format!("- {f}"))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        reply = reply.content(format!("Couldn't find a match for:\n{lines}"));
+    }
+
+    ctx.send(reply).await?;
+
+    Ok(())
+}
+
+/// Searches for a track by name and lets the user pick one of the results, which is
+/// then posted and archived as if they had posted its link.
+#[poise::command(slash_command, guild_only)]
+pub async fn search(ctx: CommandCtx<'_>, query: String) -> Result<()> {
+    let handler = ctx.data();
+
+    if handler.in_flight.is_closed() {
+        return reply_ephemeral(
+            ctx,
+            String::from("The bot is restarting, try again in a minute."),
+        )
+        .await;
+    }
+
+    ctx.defer_ephemeral().await?;
+
+    let mut options = vec![];
+
+    if let Some(spotify_client) = handler.clients.spotify() {
+        match spotify::search_tracks(&spotify_client, query.as_str(), SEARCH_RESULTS).await {
+            Ok(tracks) => {
+                for track in tracks {
+                    let Some(id) = track.id.as_ref() else {
+                        continue;
+                    };
+
+                    let artists = track
+                        .artists
+                        .iter()
+                        .map(|a| a.name.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ");
+
+                    options.push(search_option(
+                        format!("{} by {artists}", track.name),
+                        format!("Spotify · {}", track.album.name),
+                        cards::spotify_url(&IdType::Track(id.id().to_string())),
+                    ));
+                }
+            }
+            Err(e) => error!("failed to search spotify: {e}"),
+        }
+    }
+
+    if let Some(tidal_client) = handler.clients.tidal() {
+        match tidal::search_tracks(&tidal_client, query.as_str(), SEARCH_RESULTS as usize).await {
+            Ok(tracks) => {
+                for track in tracks {
+                    let Some(card) = Card::from_tidal(&track, None, None) else {
+                        continue;
+                    };
+
+                    options.push(search_option(
+                        format!("{} by {}", card.title, card.artists.join(", ")),
+                        card.album.map_or_else(
+                            || String::from("TIDAL"),
+                            |album| format!("TIDAL · {album}"),
+                        ),
+                        cards::tidal_url(&track.resource()),
+                    ));
+                }
+            }
+            Err(e) => error!("failed to search tidal: {e}"),
+        }
+    }
+
+    if options.is_empty() {
+        return reply_ephemeral(ctx, format!("Couldn't find any tracks for \"{query}\".")).await;
+    }
+
+    let custom_id = format!("{}search", ctx.id());
+
+    ctx.send(
+        poise::CreateReply::default()
+            .content(format!("Results for \"{query}\":"))
+            .components(vec![CreateActionRow::SelectMenu(
+                CreateSelectMenu::new(custom_id.as_str(), CreateSelectMenuKind::String { options })
+                    .placeholder("Pick a track to share"),
+            )])
+            .ephemeral(true),
+    )
+    .await?;
+
+    let Some(interaction) = ComponentInteractionCollector::new(ctx.serenity_context())
+        .author_id(ctx.author().id)
+        .channel_id(ctx.channel_id())
+        .timeout(SEARCH_TIMEOUT)
+        .filter(move |i| i.data.custom_id == custom_id)
+        .await
+    else {
+        return Ok(());
+    };
+
+    let ComponentInteractionDataKind::StringSelect { values } = &interaction.data.kind else {
+        return Ok(());
+    };
+
+    let Some(link) = values.first() else {
+        return Ok(());
+    };
+
+    interaction
+        .create_response(
+            ctx.http(),
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content("Shared it in the channel.")
+                    .components(vec![]),
+            ),
+        )
+        .await?;
+
+    // Embeds are suppressed since the card replying to the post shows the track.
+    let mut posted = ctx
+        .channel_id()
+        .send_message(
+            ctx.http(),
+            CreateMessage::new()
+                .content(format!("{} shared {link}", ctx.author().mention()))
+                .allowed_mentions(CreateAllowedMentions::new())
+                .flags(MessageFlags::SUPPRESS_EMBEDS),
+        )
+        .await?;
+
+    posted.author = ctx.author().clone();
+    posted.guild_id = ctx.guild_id();
+
+    handler
+        .in_flight
+        .track_future(handler.handle_message(
+            ctx.serenity_context().clone(),
+            posted,
+            Recipients::Automatic,
+        ))
+        .await;
+
+    Ok(())
+}
+
+fn search_option(label: String, description: String, link: String) -> CreateSelectMenuOption {
+    CreateSelectMenuOption::new(truncate(label, SELECT_TEXT_LIMIT), link)
+        .description(truncate(description, SELECT_TEXT_LIMIT))
+}
+
+fn truncate(mut text: String, limit: usize) -> String {
+    if let Some((i, _)) = text.char_indices().nth(limit) {
+        text.truncate(i);
+    }
+
+    text
+}
+
+/// Shows what has been posted in this server: totals per service and month, and the
+/// top posters, artists and genres.
+#[poise::command(slash_command, guild_only)]
+pub async fn stats(ctx: CommandCtx<'_>) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let stats = match stats::for_guild(&ctx.data().conn, guild_id.as_str()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to load stats: {e}");
+            return Err(DiscordError.into());
+        }
+    };
+
+    if stats.is_empty() {
+        return reply_ephemeral(ctx, String::from("Nothing has been posted here yet.")).await;
+    }
+
+    ctx.send(
+        poise::CreateReply::default()
+            .embed(stats.embed())
+            .allowed_mentions(CreateAllowedMentions::new()),
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
+pub enum LeaderboardPeriod {
+    #[name = "This week"]
+    Week,
+    #[name = "This month"]
+    Month,
+}
+
+/// Shows who has posted the most tracks in this server this week or month.
+#[poise::command(slash_command, guild_only)]
+pub async fn leaderboard(ctx: CommandCtx<'_>, period: LeaderboardPeriod) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let (since, title) = match period {
+        LeaderboardPeriod::Week => (stats::week_start(), "Top posters this week"),
+        LeaderboardPeriod::Month => (stats::month_start(), "Top posters this month"),
+    };
+
+    let posters = match stats::leaderboard(&ctx.data().conn, guild_id.as_str(), since.as_str()) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("failed to load leaderboard: {e}");
+            return Err(DiscordError.into());
+        }
+    };
+
+    ctx.send(
+        poise::CreateReply::default()
+            .embed(stats::leaderboard_embed(title, &posters))
+            .allowed_mentions(CreateAllowedMentions::new()),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Records which resource a link should match on another service. The mapping is used
+/// instead of the automatic matcher from then on, and can optionally be applied to the
+/// admin's own playlist in the guild if it already received the wrong match.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    default_member_permissions = "MANAGE_GUILD"
+)]
+pub async fn fix_match(
+    ctx: CommandCtx<'_>,
+    source_link: String,
+    target_link: String,
+    replace_in_playlists: Option<bool>,
+) -> Result<()> {
+    let (Some(source), Some(target)) = (
+        ResourceKey::from_link(source_link.as_str()),
+        ResourceKey::from_link(target_link.as_str()),
+    ) else {
+        return reply_ephemeral(
+            ctx,
+            String::from("Both links need to be Spotify or TIDAL album or track links."),
+        )
+        .await;
+    };
+
+    if source.service == target.service {
+        return reply_ephemeral(
+            ctx,
+            String::from("The links need to be on different services."),
+        )
+        .await;
+    }
+
+    if source.kind != target.kind {
+        return reply_ephemeral(
+            ctx,
+            String::from("The links need to both be albums or both be tracks."),
+        )
+        .await;
+    }
+
+    let discord_user_id = ctx.author().id.to_string();
+
+    let user =
+        match first_or_create_user_by_discord_user_id(&ctx.data().conn, discord_user_id.as_str()) {
+            Ok(u) => u,
+            Err(e) => {
+                error!("error creating user: {e}");
+                return Err(DiscordError.into());
+            }
+        };
+
+    let Some(user_id) = user.id else {
+        return Err(DiscordError.into());
+    };
+
+    ctx.defer_ephemeral().await?;
+
+    let handler = ctx.data();
+
+    // What links like this are matched to today, so it can be taken back out of
+    // playlists. That is the current override if there is one, otherwise whatever the
+    // automatic matcher picks.
+    let wrong = match (
+        source.to_tidal(),
+        handler.clients.tidal(),
+        handler.clients.spotify(),
+    ) {
+        (Some(resource), Some(tidal_client), Some(spotify_client))
+            if replace_in_playlists.unwrap_or(false) =>
+        {
+            let current = match matching::spotify_override(&handler.conn, &resource) {
+                Ok(o) => o,
+                Err(e) => {
+                    error!("error looking up current match override: {e}");
+                    return Err(DiscordError.into());
+                }
+            };
+
+            match current {
+                Some(id) => vec![id],
+                None => {
+                    let full = tidal::get_full_tidal_resources(&tidal_client, vec![resource]).await;
+                    tidal::match_spotify_resources(&spotify_client, &full)
+                        .await
+                        .into_iter()
+                        .filter_map(std::result::Result::ok)
+                        .collect()
+                }
+            }
+        }
+        _ => vec![],
+    };
+
+    if let Err(e) = upsert_match_override(
+        &handler.conn,
+        &MatchOverride {
+            source_service: source.service.to_string(),
+            source_kind: source.kind.to_string(),
+            source_id: source.id.clone(),
+            target_service: target.service.to_string(),
+            target_kind: target.kind.to_string(),
+            target_id: target.id.clone(),
+            created_by_user_id: user_id,
+        },
+    ) {
+        error!("error saving match override: {e}");
+        return Err(DiscordError.into());
+    }
+
+    info!(
+        "saved match override {}:{} -> {}:{}",
+        source.service, source.id, target.service, target.id
+    );
+
+    let target_name = pages::service_display_name(target.service);
+    let mut content = format!(
+        "Got it, that link will be matched to the one you gave on {target_name} from now on."
+    );
+
+    if replace_in_playlists.unwrap_or(false)
+        && let Some(guild_id) = ctx.guild_id()
+    {
+        let guild_id = guild_id.to_string();
+
+        let updated = if let Some(right) = target.to_spotify() {
+            handler
+                .replace_spotify_match(guild_id.as_str(), user_id, &wrong, right)
+                .await
+        } else if let Some(right) = target.to_tidal() {
+            handler
+                .add_tidal_match(guild_id.as_str(), user_id, right)
+                .await
+        } else {
+            0
+        };
+
+        content.push_str(
+            format!(" Updated {updated} of your {target_name} playlist(s) in this server.")
+                .as_str(),
+        );
+
+        if target.service == "tidal" && updated > 0 {
+            content.push_str(" The wrong match has to be removed from TIDAL playlists by hand.");
+        }
+    }
+
+    reply_ephemeral(ctx, content).await
+}
+
+/// Adds the links in a message to the invoking user's playlists only, which is how
+/// users in opt-in only mode archive anything.
+#[poise::command(context_menu_command = "Add to my playlists", guild_only)]
+pub async fn add_to_my_playlists(ctx: CommandCtx<'_>, mut msg: Message) -> Result<()> {
+    let handler = ctx.data();
+
+    // Not being registered is expected, anything else is a database problem. The error
+    // isn't Send, so it's dropped before replying.
+    let user_id =
+        match get_user_by_discord_user_id(&handler.conn, ctx.author().id.to_string().as_str()) {
+            Ok(u) => Ok(u.id),
+            Err(e)
+                if matches!(
+                    e.downcast_ref::<rusqlite::Error>(),
+                    Some(rusqlite::Error::QueryReturnedNoRows)
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => {
+                error!("error fetching user: {e}");
+                Err(())
+            }
+        };
+
+    let Ok(user_id) = user_id else {
+        return reply_ephemeral(
+            ctx,
+            String::from("Couldn't look up your playlists right now, try again later."),
+        )
+        .await;
+    };
+
+    let Some(user_id) = user_id else {
+        return reply_ephemeral(
+            ctx,
+            String::from("You don't have any playlists yet. Run `/register_playlist` to add one."),
+        )
+        .await;
+    };
+
+    if handler.in_flight.is_closed() {
+        return reply_ephemeral(
+            ctx,
+            String::from("The bot is restarting, try again in a minute."),
+        )
+        .await;
+    }
+
+    let guild_id = msg.guild_id.or(ctx.guild_id()).map(|g| g.to_string());
+
+    if guild_id.is_some_and(|g| handler.author_opted_out(&msg, g.as_str())) {
+        return reply_ephemeral(
+            ctx,
+            String::from(
+                "The author of that message has opted out of having their posts archived.",
+            ),
+        )
+        .await;
+    }
+
+    ctx.defer_ephemeral().await?;
+
+    // Messages resolved from an interaction don't carry their guild.
+    msg.guild_id = msg.guild_id.or(ctx.guild_id());
+
+    let added = handler
+        .in_flight
+        .track_future(handler.handle_message(
+            ctx.serenity_context().clone(),
+            msg,
+            Recipients::User(user_id),
+        ))
+        .await;
+
+    reply_ephemeral(ctx, added_summary(&added)).await
+}
+
+/// Describes what "Add to my playlists" actually put in the user's playlists.
+fn added_summary(added: &[Added]) -> String {
+    if added.is_empty() {
+        return String::from("That message doesn't have any links that can be added.");
+    }
+
+    let mut totals: Vec<(&str, usize)> = vec![];
+
+    for a in added {
+        match totals.iter_mut().find(|(s, _)| *s == a.service) {
+            Some((_, items)) => *items += a.items,
+            None => totals.push((a.service, a.items)),
+        }
+    }
+
+    let lines: Vec<String> = totals
+        .into_iter()
+        .filter(|(_, items)| *items > 0)
+        .map(|(service, items)| {
+            let unit = if service == "youtube" {
+                "video"
+            } else {
+                "track"
             };
+            let plural = if items == 1 { "" } else { "s" };
+            format!(
+                "{items} {unit}{plural} to your {} playlist",
+                pages::service_display_name(service)
+            )
+        })
+        .collect();
 
-            match spotify_client
-                .playlist_add_items(playlist_id, track_ids.clone(), None)
-                .await
-            {
-                Ok(_) => {
-                    info!("Added tracks to playlist");
-                }
-                Err(e) => {
-                    error!("Failed to add tracks to playlist: {e}");
-                }
-            }
-        }
+    if lines.is_empty() {
+        return String::from(
+            "Nothing from that message could be added. Check that you have a playlist registered in this server for the service it links to.",
+        );
+    }
 
-        if !user_guilds.is_empty() {
-            let mills500 = std::time::Duration::from_millis(500);
-            task::sleep(mills500).await;
-            info!("acknowledging message");
-            _ = new_message.react(&ctx, Unicode(String::from("✅"))).await;
-        }
+    format!("Added {}.", lines.join(", "))
+}
+
+/// Switches between archiving every link posted in servers with a registered playlist
+/// and only the messages picked with "Add to my playlists".
+#[poise::command(slash_command)]
+pub async fn opt_in_only(ctx: CommandCtx<'_>, enabled: bool) -> Result<()> {
+    let discord_user_id = ctx.author().id.to_string();
 
-        let album_image_urls = get_album_images(&self.spotify_client, &spotify_ids).await;
+    let user =
+        match first_or_create_user_by_discord_user_id(&ctx.data().conn, discord_user_id.as_str()) {
+            Ok(u) => u,
+            Err(e) => {
+                error!("error creating user: {e}");
+                return Err(DiscordError.into());
+            }
+        };
 
-        for image in album_image_urls {
-            // Send the image URL as a reply to the original message
-            let _ = new_message.reply(&ctx.http, image).await;
+    let Some(user_id) = user.id else {
+        return Err(DiscordError.into());
+    };
 
-            info!("sent track art to channel");
-        }
+    if let Err(e) = update_user_opt_in_only(&ctx.data().conn, user_id, enabled) {
+        error!("error updating opt in only mode: {e}");
+        return Err(DiscordError.into());
     }
+
+    let content = if enabled {
+        "Only messages you pick with **Apps → Add to my playlists** will be added to your playlists now."
+    } else {
+        "Every link posted will be added to your playlists again."
+    };
+
+    reply_ephemeral(ctx, String::from(content)).await
 }
 
 #[poise::command(slash_command)]
@@ -483,6 +1871,8 @@ pub async fn authorize_youtube(ctx: CommandCtx<'_>) -> Result<()> {
         None => return Err(DiscordError.into()),
     };
 
+    let guild_name = ctx.guild().map(|g| g.name.clone());
+
     let user = match first_or_create_user_by_discord_user_id(&ctx.data().conn, discord_user_id) {
         Ok(u) => u,
         Err(e) => {
@@ -497,7 +1887,7 @@ pub async fn authorize_youtube(ctx: CommandCtx<'_>) -> Result<()> {
 
     let _ = match first_or_create_user_guild_by_user_id_and_guild_id(
         &ctx.data().conn,
-        guild_id,
+        guild_id.clone(),
         user_id,
         "youtube",
     ) {
@@ -520,11 +1910,16 @@ pub async fn authorize_youtube(ctx: CommandCtx<'_>) -> Result<()> {
 
     _ = match create_auth_request(
         &ctx.data().conn,
-        state.into_secret(),
-        discord_user_id,
-        None,
-        None,
-        "youtube",
+        AuthRequest {
+            discord_user_id: discord_user_id.to_string(),
+            state: state.into_secret(),
+            pkce_code_challenge: None,
+            pkce_code_verifier: None,
+            for_service: String::from("youtube"),
+            discord_guild_id: Some(guild_id),
+            discord_guild_name: guild_name,
+            interaction_token: interaction_token(ctx),
+        },
     ) {
         Ok(u) => u,
         Err(e) => {
@@ -559,6 +1954,8 @@ pub async fn authorize_spotify(ctx: CommandCtx<'_>) -> Result<()> {
         None => return Err(DiscordError.into()),
     };
 
+    let guild_name = ctx.guild().map(|g| g.name.clone());
+
     let user = match first_or_create_user_by_discord_user_id(&ctx.data().conn, discord_user_id) {
         Ok(u) => u,
         Err(e) => {
@@ -573,7 +1970,7 @@ pub async fn authorize_spotify(ctx: CommandCtx<'_>) -> Result<()> {
 
     let _ = match first_or_create_user_guild_by_user_id_and_guild_id(
         &ctx.data().conn,
-        guild_id,
+        guild_id.clone(),
         user_id,
         "spotify",
     ) {
@@ -604,11 +2001,16 @@ pub async fn authorize_spotify(ctx: CommandCtx<'_>) -> Result<()> {
 
     _ = match create_auth_request(
         &ctx.data().conn,
-        spotify_client.oauth.state,
-        discord_user_id,
-        None,
-        None,
-        "spotify",
+        AuthRequest {
+            discord_user_id: discord_user_id.to_string(),
+            state: spotify_client.oauth.state,
+            pkce_code_challenge: None,
+            pkce_code_verifier: None,
+            for_service: String::from("spotify"),
+            discord_guild_id: Some(guild_id),
+            discord_guild_name: guild_name,
+            interaction_token: interaction_token(ctx),
+        },
     ) {
         Ok(u) => u,
         Err(e) => {
@@ -642,6 +2044,8 @@ pub async fn authorize_tidal(ctx: CommandCtx<'_>) -> Result<()> {
         None => return Err(DiscordError.into()),
     };
 
+    let guild_name = ctx.guild().map(|g| g.name.clone());
+
     let user = match first_or_create_user_by_discord_user_id(&ctx.data().conn, discord_user_id) {
         Ok(u) => u,
         Err(e) => {
@@ -656,7 +2060,7 @@ pub async fn authorize_tidal(ctx: CommandCtx<'_>) -> Result<()> {
 
     let _ = match first_or_create_user_guild_by_user_id_and_guild_id(
         &ctx.data().conn,
-        guild_id,
+        guild_id.clone(),
         user_id,
         "tidal",
     ) {
@@ -682,11 +2086,16 @@ pub async fn authorize_tidal(ctx: CommandCtx<'_>) -> Result<()> {
 
     _ = match create_auth_request(
         &ctx.data().conn,
-        state.into_secret(),
-        discord_user_id,
-        Some(String::from(pkce_code.as_str())),
-        Some(String::from(pkce_verifier.into_secret().as_str())),
-        "tidal",
+        AuthRequest {
+            discord_user_id: discord_user_id.to_string(),
+            state: state.into_secret(),
+            pkce_code_challenge: Some(String::from(pkce_code.as_str())),
+            pkce_code_verifier: Some(String::from(pkce_verifier.into_secret().as_str())),
+            for_service: String::from("tidal"),
+            discord_guild_id: Some(guild_id),
+            discord_guild_name: guild_name,
+            interaction_token: interaction_token(ctx),
+        },
     ) {
         Ok(u) => u,
         Err(e) => {
@@ -710,6 +2119,117 @@ pub async fn authorize_tidal(ctx: CommandCtx<'_>) -> Result<()> {
     }
 }
 
+fn interaction_token(ctx: CommandCtx<'_>) -> Option<String> {
+    match ctx {
+        poise::Context::Application(a) => Some(a.interaction.token.clone()),
+        poise::Context::Prefix(_) => None,
+    }
+}
+
+/// What the auth server needs to tell a user how their authorization went, copied out
+/// of the `AuthRequest` before it is consumed by the token exchange.
+pub struct AuthNotice {
+    pub discord_user_id: String,
+    pub service: String,
+    pub guild_id: Option<String>,
+    pub guild_name: Option<String>,
+    pub interaction_token: Option<String>,
+}
+
+impl From<&AuthRequest> for AuthNotice {
+    fn from(value: &AuthRequest) -> Self {
+        Self {
+            discord_user_id: value.discord_user_id.clone(),
+            service: value.for_service.clone(),
+            guild_id: value.discord_guild_id.clone(),
+            guild_name: value.discord_guild_name.clone(),
+            interaction_token: value.interaction_token.clone(),
+        }
+    }
+}
+
+fn has_registered_playlist(conn: &Arc<Mutex<Connection>>, notice: &AuthNotice) -> bool {
+    let Some(guild_id) = notice.guild_id.clone() else {
+        return false;
+    };
+
+    let Ok(user) = get_user_by_discord_user_id(conn, notice.discord_user_id.as_str()) else {
+        return false;
+    };
+
+    let Some(user_id) = user.id else {
+        return false;
+    };
+
+    get_user_guild_by_user_id_and_guild_id_and_service(
+        conn,
+        guild_id,
+        user_id,
+        notice.service.as_str(),
+    )
+    .is_ok_and(|g| g.playlist_id.is_some())
+}
+
+/// Lets a user know in Discord how the authorization they started from a slash command
+/// went, since the callback page lives outside of Discord. The original ephemeral reply
+/// is edited when its interaction token is still valid, otherwise the user is sent a DM.
+pub async fn notify_auth_result(
+    http: &Arc<Http>,
+    conn: &Arc<Mutex<Connection>>,
+    notice: AuthNotice,
+    succeeded: bool,
+) {
+    let server = notice
+        .guild_name
+        .as_deref()
+        .map_or_else(|| String::from("your server"), |n| format!("**{n}**"));
+    let service_name = pages::service_display_name(notice.service.as_str());
+
+    let content = if succeeded && has_registered_playlist(conn, &notice) {
+        format!(
+            "You're authorized with {service_name} for {server}! Links posted there will keep going to your registered playlist."
+        )
+    } else if succeeded {
+        format!(
+            "You're authorized with {service_name} for {server}! Next, run `/register_playlist` there with a link to the {service_name} playlist you want to archive to."
+        )
+    } else {
+        format!(
+            "Authorizing with {service_name} for {server} didn't go through. Run `/authorize_{}` to get a fresh link and try again.",
+            notice.service
+        )
+    };
+
+    if let Some(token) = notice.interaction_token.as_deref() {
+        match EditInteractionResponse::new()
+            .content(content.clone())
+            .execute(http.as_ref(), token)
+            .await
+        {
+            Ok(_) => {
+                info!("updated authorization reply");
+                return;
+            }
+            Err(e) => {
+                warn!("failed to update authorization reply, sending a DM instead: {e}");
+            }
+        }
+    }
+
+    let Ok(id) = notice.discord_user_id.parse::<u64>() else {
+        error!("invalid discord user id {}", notice.discord_user_id);
+        return;
+    };
+
+    match UserId::new(id)
+        .direct_message(http, CreateMessage::new().content(content))
+        .await
+    {
+        Ok(_) => info!("sent authorization result"),
+        Err(e) => error!("failed to send authorization result: {e}"),
+    }
+}
+
 fn extract_playlist_id(service: &str, msg: &str) -> Option<String> {
     match service {
         "spotify" => spotify::extract_playlist_id(msg),
@@ -721,16 +2241,28 @@ fn extract_playlist_id(service: &str, msg: &str) -> Option<String> {
 
 #[poise::command(slash_command)]
 pub async fn register_playlist(ctx: CommandCtx<'_>, playlist_link: String) -> Result<()> {
-    let service = if spotify::contains_spotify_link(playlist_link.as_str()) {
-        "spotify"
-    } else if tidal::contains_tidal_link(playlist_link.as_str()) {
-        "tidal"
-    } else if youtube::contains_youtube_link(playlist_link.as_str()) {
-        "youtube"
-    } else {
+    let Some(service) = link_service(playlist_link.as_str()) else {
         return Err(DiscordError.into());
     };
 
+    if !service_enabled(service) {
+        info!("rejecting playlist for disabled service {service}");
+        return match ctx
+            .send(
+                poise::CreateReply::default()
+                    .content(format!(
+                        "{} is not enabled on this bot.",
+                        pages::service_display_name(service)
+                    ))
+                    .ephemeral(true),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+    }
+
     let Some(playlist_id) = extract_playlist_id(service, playlist_link.as_str()) else {
         error!("failed to parse playlist id");
         let s = ctx
@@ -789,3 +2321,628 @@ pub async fn register_playlist(ctx: CommandCtx<'_>, playlist_link: String) -> Re
         Err(e) => Err(e.into()),
     }
 }
+
+/// Registers the server's own playlist for a service. It keeps receiving links no
+/// matter who comes and goes, and is written with the token of the admin who registered
+/// it until ownership is transferred.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    default_member_permissions = "MANAGE_GUILD"
+)]
+pub async fn register_guild_playlist(ctx: CommandCtx<'_>, playlist_link: String) -> Result<()> {
+    let Some(service) = link_service(playlist_link.as_str()) else {
+        return reply_ephemeral(
+            ctx,
+            String::from("That isn't a Spotify, TIDAL or YouTube playlist link."),
+        )
+        .await;
+    };
+
+    let service_name = pages::service_display_name(service);
+
+    if !service_enabled(service) {
+        return reply_ephemeral(ctx, format!("{service_name} is not enabled on this bot.")).await;
+    }
+
+    let Some(playlist_id) = extract_playlist_id(service, playlist_link.as_str()) else {
+        return reply_ephemeral(
+            ctx,
+            String::from("Check your playlist link, we were not able to parse it."),
+        )
+        .await;
+    };
+
+    let Some(guild_id) = ctx.guild_id() else {
+        return Err(DiscordError.into());
+    };
+
+    let discord_user_id = ctx.author().id.to_string();
+
+    let user_id = match get_user_by_discord_user_id(&ctx.data().conn, discord_user_id.as_str()) {
+        Ok(u) => u.id,
+        Err(e) => {
+            info!("user has not authorized: {e}");
+            None
+        }
+    };
+
+    let authorized = user_id.is_some_and(|id| {
+        get_oauth_token_by_user_id_and_service(&ctx.data().conn, id, service).is_ok()
+    });
+
+    let (Some(user_id), true) = (user_id, authorized) else {
+        return reply_ephemeral(
+            ctx,
+            format!(
+                "The server's playlist is written with your {service_name} account. Run `/authorize_{service}` first, then try again."
+            ),
+        )
+        .await;
+    };
+
+    if let Err(e) = upsert_guild_playlist(
+        &ctx.data().conn,
+        guild_id.to_string().as_str(),
+        service,
+        playlist_id.as_str(),
+        user_id,
+    ) {
+        error!("failed to register guild playlist: {e}");
+        return Err(DiscordError.into());
+    }
+
+    reply_ephemeral(
+        ctx,
+        format!(
+            "This server's {service_name} playlist is registered and will be written with your account. Use `/transfer_guild_playlist` to hand it to someone else."
+        ),
+    )
+    .await
+}
+
+/// Hands the server's playlist for a service to another member, whose token is used to
+/// write to it from then on.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    default_member_permissions = "MANAGE_GUILD"
+)]
+pub async fn transfer_guild_playlist(
+    ctx: CommandCtx<'_>,
+    service: ServiceChoice,
+    new_owner: serenity::all::User,
+) -> Result<()> {
+    let service = service.as_str();
+    let service_name = pages::service_display_name(service);
+
+    let Some(guild_id) = ctx.guild_id() else {
+        return Err(DiscordError.into());
+    };
+    let guild_id = guild_id.to_string();
+
+    let registered = match get_guild_playlist_by_guild_id_and_service(
+        &ctx.data().conn,
+        guild_id.as_str(),
+        service,
+    ) {
+        Ok(p) => p.is_some(),
+        Err(e) => {
+            error!("failed to get guild playlist: {e}");
+            return Err(DiscordError.into());
+        }
+    };
+
+    if !registered {
+        return reply_ephemeral(
+            ctx,
+            format!(
+                "This server has no {service_name} playlist. Register one with `/register_guild_playlist`."
+            ),
+        )
+        .await;
+    }
+
+    let owner_id =
+        match get_user_by_discord_user_id(&ctx.data().conn, new_owner.id.to_string().as_str()) {
+            Ok(u) => u.id,
+            Err(e) => {
+                info!("new owner has not authorized: {e}");
+                None
+            }
+        };
+
+    let authorized = owner_id.is_some_and(|id| {
+        get_oauth_token_by_user_id_and_service(&ctx.data().conn, id, service).is_ok()
+    });
+
+    let (Some(owner_id), true) = (owner_id, authorized) else {
+        return reply_ephemeral(
+            ctx,
+            format!(
+                "{} needs to run `/authorize_{service}` before they can own the server's {service_name} playlist.",
+                new_owner.name
+            ),
+        )
+        .await;
+    };
+
+    if let Err(e) =
+        update_guild_playlist_owner(&ctx.data().conn, guild_id.as_str(), service, owner_id)
+    {
+        error!("failed to transfer guild playlist: {e}");
+        return Err(DiscordError.into());
+    }
+
+    info!("transferred {service} guild playlist for {guild_id}");
+
+    reply_ephemeral(
+        ctx,
+        format!(
+            "The server's {service_name} playlist now belongs to {}. Make sure they can edit it on {service_name}.",
+            new_owner.name
+        ),
+    )
+    .await
+}
+
+/// Shows and changes how the bot behaves in this server.
+#[poise::command(
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    default_member_permissions = "MANAGE_GUILD",
+    subcommands(
+        "settings_show",
+        "settings_reaction",
+        "settings_album_art",
+        "settings_expand_albums",
+        "settings_ignore_bots",
+        "settings_digest",
+        "settings_allow_channel",
+        "settings_deny_channel",
+        "settings_clear_channel",
+        "settings_reset"
+    ),
+    subcommand_required,
+    rename = "settings"
+)]
+pub async fn guild_settings(_: CommandCtx<'_>) -> Result<()> {
+    Ok(())
+}
+
+fn guild_id_string(ctx: CommandCtx<'_>) -> Result<String> {
+    match ctx.guild_id() {
+        Some(id) => Ok(id.to_string()),
+        None => Err(DiscordError.into()),
+    }
+}
+
+async fn reply_settings(ctx: CommandCtx<'_>, settings: &Settings) -> Result<()> {
+    reply_ephemeral(
+        ctx,
+        format!("Settings for this server:\n{}", settings.describe()),
+    )
+    .await
+}
+
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "show",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_show(ctx: CommandCtx<'_>) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    reply_settings(
+        ctx,
+        &settings::for_guild(&ctx.data().conn, guild_id.as_str()),
+    )
+    .await
+}
+
+/// Sets the emoji reacted to messages once their links were added. Leave out the emoji
+/// to go back to the default.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "reaction",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_reaction(
+    ctx: CommandCtx<'_>,
+    service: ServiceChoice,
+    emoji: Option<String>,
+) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let emoji = emoji
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty());
+
+    if let Some(e) = &emoji
+        && !reactable(ctx, e.as_str())
+    {
+        return reply_ephemeral(
+            ctx,
+            format!("{e} isn't an emoji that can be reacted with. Use a single emoji or one of this server's custom emoji."),
+        )
+        .await;
+    }
+
+    let updated = settings::update(&ctx.data().conn, guild_id.as_str(), |s| match service {
+        ServiceChoice::Spotify => s.spotify_reaction = emoji,
+        ServiceChoice::Tidal => s.tidal_reaction = emoji,
+        ServiceChoice::Youtube => s.youtube_reaction = emoji,
+    })?;
+
+    reply_settings(ctx, &updated).await
+}
+
+/// Whether `emoji` is a single standard emoji or one of the guild's own custom emoji.
+/// Anything else would only fail once the bot tries to react with it.
+fn reactable(ctx: CommandCtx<'_>, emoji: &str) -> bool {
+    match ReactionType::try_from(emoji) {
+        Ok(ReactionType::Unicode(u)) => emojis::get(u.as_str()).is_some(),
+        Ok(ReactionType::Custom { id, .. }) => {
+            ctx.guild().is_some_and(|g| g.emojis.contains_key(&id))
+        }
+        _ => false,
+    }
+}
+
+/// Sets whether messages with links get a card with the item's art and links replied.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "album_art",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_album_art(ctx: CommandCtx<'_>, enabled: bool) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let updated = settings::update(&ctx.data().conn, guild_id.as_str(), |s| {
+        s.album_art = Some(enabled);
+    })?;
+
+    reply_settings(ctx, &updated).await
+}
+
+/// Sets whether album links add every track on the album. When off only track links
+/// are added.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "expand_albums",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_expand_albums(ctx: CommandCtx<'_>, enabled: bool) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let updated = settings::update(&ctx.data().conn, guild_id.as_str(), |s| {
+        s.expand_albums = Some(enabled);
+    })?;
+
+    reply_settings(ctx, &updated).await
+}
+
+/// Sets whether messages from bots and webhooks are skipped.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "ignore_bots",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_ignore_bots(ctx: CommandCtx<'_>, enabled: bool) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let updated = settings::update(&ctx.data().conn, guild_id.as_str(), |s| {
+        s.ignore_bots = Some(enabled);
+    })?;
+
+    reply_settings(ctx, &updated).await
+}
+
+/// Posts a weekly digest of what was posted to a channel, or stops it if no channel is
+/// given. The first one comes a week after it's turned on.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "digest",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_digest(ctx: CommandCtx<'_>, channel: Option<GuildChannel>) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let mut started = false;
+
+    let updated = settings::update(&ctx.data().conn, guild_id.as_str(), |s| {
+        started = s.digest_channel_id.is_none() && channel.is_some();
+        s.digest_channel_id = channel.map(|c| c.id.to_string());
+    })?;
+
+    if started {
+        digest::start(&ctx.data().conn, guild_id.as_str());
+    }
+
+    reply_settings(ctx, &updated).await
+}
+
+async fn set_channel_rule(ctx: CommandCtx<'_>, channel: &GuildChannel, allow: bool) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    if let Err(e) = upsert_channel_rule(
+        &ctx.data().conn,
+        &ChannelRule {
+            discord_guild_id: guild_id.clone(),
+            channel_id: channel.id.to_string(),
+            allow,
+        },
+    ) {
+        error!("failed to save channel rule: {e}");
+        return Err(DiscordError.into());
+    }
+
+    reply_settings(
+        ctx,
+        &settings::for_guild(&ctx.data().conn, guild_id.as_str()),
+    )
+    .await
+}
+
+/// Watches a channel, or every channel in a category, for links. Once any channel is
+/// allowed, links in channels that aren't are ignored.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "allow_channel",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_allow_channel(ctx: CommandCtx<'_>, channel: GuildChannel) -> Result<()> {
+    set_channel_rule(ctx, &channel, true).await
+}
+
+/// Ignores links in a channel, or every channel in a category.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "deny_channel",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_deny_channel(ctx: CommandCtx<'_>, channel: GuildChannel) -> Result<()> {
+    set_channel_rule(ctx, &channel, false).await
+}
+
+/// Removes the allow or deny rule for a channel or category.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "clear_channel",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_clear_channel(ctx: CommandCtx<'_>, channel: GuildChannel) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    if let Err(e) = delete_channel_rule(
+        &ctx.data().conn,
+        guild_id.as_str(),
+        channel.id.to_string().as_str(),
+    ) {
+        error!("failed to delete channel rule: {e}");
+        return Err(DiscordError.into());
+    }
+
+    reply_settings(
+        ctx,
+        &settings::for_guild(&ctx.data().conn, guild_id.as_str()),
+    )
+    .await
+}
+
+/// Puts every setting other than the channel rules back to its default.
+#[poise::command(
+    slash_command,
+    guild_only,
+    rename = "reset",
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn settings_reset(ctx: CommandCtx<'_>) -> Result<()> {
+    let guild_id = guild_id_string(ctx)?;
+
+    let updated = settings::update(&ctx.data().conn, guild_id.as_str(), |s| {
+        *s = GuildSettings {
+            discord_guild_id: s.discord_guild_id.clone(),
+            ..Default::default()
+        };
+    })?;
+
+    reply_settings(ctx, &updated).await
+}
+
+#[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
+pub enum PrivacyScope {
+    #[name = "This server"]
+    ThisServer,
+    #[name = "Everywhere"]
+    Everywhere,
+}
+
+/// The guild a privacy choice applies to, `None` meaning every guild.
+fn privacy_guild(ctx: CommandCtx<'_>, scope: PrivacyScope) -> Result<Option<String>> {
+    match scope {
+        PrivacyScope::Everywhere => Ok(None),
+        PrivacyScope::ThisServer => match ctx.guild_id() {
+            Some(id) => Ok(Some(id.to_string())),
+            None => Err(DiscordError.into()),
+        },
+    }
+}
+
+/// Controls whether the links you post are archived into other members' playlists.
+#[poise::command(
+    slash_command,
+    subcommands("privacy_opt_out", "privacy_opt_in", "privacy_status"),
+    subcommand_required
+)]
+pub async fn privacy(_: CommandCtx<'_>) -> Result<()> {
+    Ok(())
+}
+
+/// Stops the links you post from being archived, in this server or everywhere.
+#[poise::command(slash_command, rename = "opt_out")]
+pub async fn privacy_opt_out(ctx: CommandCtx<'_>, scope: PrivacyScope) -> Result<()> {
+    let guild_id = privacy_guild(ctx, scope)?;
+
+    if let Err(e) = create_privacy_opt_out(
+        &ctx.data().conn,
+        ctx.author().id.to_string().as_str(),
+        guild_id.as_deref(),
+    ) {
+        error!("failed to save privacy opt out: {e}");
+        return Err(DiscordError.into());
+    }
+
+    let content = match scope {
+        PrivacyScope::ThisServer => {
+            "Links you post in this server won't be archived or counted in stats anymore."
+        }
+        PrivacyScope::Everywhere => {
+            "Links you post won't be archived or counted in stats in any server anymore."
+        }
+    };
+
+    reply_ephemeral(ctx, String::from(content)).await
+}
+
+/// Lets the links you post be archived again, in this server or everywhere.
+#[poise::command(slash_command, rename = "opt_in")]
+pub async fn privacy_opt_in(ctx: CommandCtx<'_>, scope: PrivacyScope) -> Result<()> {
+    let guild_id = privacy_guild(ctx, scope)?;
+
+    let removed = match delete_privacy_opt_out(
+        &ctx.data().conn,
+        ctx.author().id.to_string().as_str(),
+        guild_id.as_deref(),
+    ) {
+        Ok(r) => r,
+        Err(e) => {
+            error!("failed to remove privacy opt out: {e}");
+            return Err(DiscordError.into());
+        }
+    };
+
+    let content = match (removed, scope) {
+        (false, _) => "You weren't opted out there, nothing changed. Check `/privacy status`.",
+        (true, PrivacyScope::ThisServer) => "Links you post in this server will be archived again.",
+        (true, PrivacyScope::Everywhere) => {
+            "You're no longer opted out everywhere. Any servers you opted out of separately stay that way."
+        }
+    };
+
+    reply_ephemeral(ctx, String::from(content)).await
+}
+
+/// Shows where you have opted out.
+#[poise::command(slash_command, rename = "status")]
+pub async fn privacy_status(ctx: CommandCtx<'_>) -> Result<()> {
+    let opt_outs = match get_privacy_opt_outs_by_discord_user_id(
+        &ctx.data().conn,
+        ctx.author().id.to_string().as_str(),
+    ) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("failed to get privacy opt outs: {e}");
+            return Err(DiscordError.into());
+        }
+    };
+
+    let here = ctx.guild_id().map(|g| g.to_string());
+
+    let content = if opt_outs.contains(&None) {
+        "You're opted out everywhere, links you post are never archived."
+    } else if here.is_some() && opt_outs.contains(&here) {
+        "You're opted out in this server, links you post here aren't archived."
+    } else if opt_outs.is_empty() {
+        "You haven't opted out anywhere, links you post are archived."
+    } else {
+        "You're opted out in other servers, but links you post here are archived."
+    };
+
+    reply_ephemeral(ctx, String::from(content)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_summary_totals_each_service() {
+        let added = [
+            Added {
+                service: "spotify",
+                items: 2,
+            },
+            Added {
+                service: "youtube",
+                items: 1,
+            },
+            Added {
+                service: "spotify",
+                items: 1,
+            },
+        ];
+
+        assert_eq!(
+            added_summary(&added),
+            "Added 3 tracks to your Spotify playlist, 1 video to your YouTube playlist."
+        );
+    }
+
+    #[test]
+    fn added_summary_leaves_out_services_nothing_was_added_to() {
+        let added = [
+            Added {
+                service: "tidal",
+                items: 0,
+            },
+            Added {
+                service: "spotify",
+                items: 1,
+            },
+        ];
+
+        assert_eq!(
+            added_summary(&added),
+            "Added 1 track to your Spotify playlist."
+        );
+    }
+
+    #[test]
+    fn added_summary_explains_when_nothing_was_added() {
+        assert!(added_summary(&[]).contains("doesn't have any links"));
+        assert!(
+            added_summary(&[Added {
+                service: "tidal",
+                items: 0,
+            }])
+            .starts_with("Nothing from that message could be added.")
+        );
+    }
+
+    #[test]
+    fn truncate_keeps_short_text() {
+        assert_eq!(truncate(String::from("short"), 10), "short");
+        assert_eq!(truncate(String::from("exact"), 5), "exact");
+    }
+
+    #[test]
+    fn truncate_counts_characters_not_bytes() {
+        assert_eq!(
+            truncate(String::from("Sigur Rós – Hoppípolla"), 11),
+            "Sigur Rós –"
+        );
+    }
+}
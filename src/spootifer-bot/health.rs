@@ -0,0 +1,84 @@
+use crate::clients::AppClients;
+use crate::metrics;
+use axum::Json;
+use axum::extract::State;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use rusqlite::Connection;
+use serde_json::{Value, json};
+use serenity::all::{ConnectionStage, ShardManager};
+use std::sync::{Arc, Mutex, TryLockError};
+use tracing::error;
+
+/// Everything `/readyz` needs to decide whether the bot can do useful work.
+pub struct Readiness {
+    pub conn: Arc<Mutex<Connection>>,
+    pub shard_manager: Arc<ShardManager>,
+    pub clients: Arc<AppClients>,
+}
+
+pub async fn healthz() -> (StatusCode, &'static str) {
+    (StatusCode::OK, "ok")
+}
+
+pub async fn readyz(State(state): State<Arc<Readiness>>) -> (StatusCode, Json<Value>) {
+    let database = database_ready(&state.conn);
+    let discord = discord_ready(&state.shard_manager).await;
+    let spotify = client_ready(state.clients.spotify_expires_at());
+    let tidal = client_ready(state.clients.tidal_expires_at());
+
+    let status = if database && discord && spotify != Some(false) && tidal != Some(false) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "database": database,
+            "discord": discord,
+            "spotify": spotify.map_or(json!("disabled"), Value::from),
+            "tidal": tidal.map_or(json!("disabled"), Value::from),
+        })),
+    )
+}
+
+pub async fn export_metrics() -> String {
+    metrics::gather()
+}
+
+// Never waits for the connection, so a probe can't hold up message handling. If
+// something else holds it the database is in use, which is as good as ready.
+fn database_ready(conn: &Arc<Mutex<Connection>>) -> bool {
+    let c = match conn.try_lock() {
+        Ok(c) => c,
+        Err(TryLockError::WouldBlock) => return true,
+        Err(TryLockError::Poisoned(_)) => {
+            error!("db lock poisoned");
+            return false;
+        }
+    };
+
+    match c.query_row("SELECT 1", [], |r| r.get::<usize, i64>(0)) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("db not ready: {e}");
+            false
+        }
+    }
+}
+
+// `None` when the service is disabled.
+fn client_ready(expires_at: Option<DateTime<Utc>>) -> Option<bool> {
+    expires_at.map(|e| e > Utc::now())
+}
+
+async fn discord_ready(shard_manager: &Arc<ShardManager>) -> bool {
+    let runners = shard_manager.runners.lock().await;
+
+    !runners.is_empty()
+        && runners
+            .values()
+            .all(|r| r.stage == ConnectionStage::Connected)
+}
@@ -0,0 +1,53 @@
+use crate::config;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, Semaphore, SemaphorePermit};
+
+// Bounds concurrent playlist writes per service across every message being handled.
+static WRITE_PERMITS: LazyLock<HashMap<&'static str, Semaphore>> = LazyLock::new(|| {
+    let permits = config::get().processing.max_concurrent_writes;
+
+    ["spotify", "tidal", "youtube"]
+        .into_iter()
+        .map(|s| (s, Semaphore::new(permits)))
+        .collect()
+});
+
+static PLAYLIST_LOCKS: LazyLock<Mutex<HashMap<(String, String), Arc<AsyncMutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Held while writing to a playlist. Only one write per playlist runs at a time so the
+/// tracks from one message land contiguously and in order.
+pub struct WriteGuard {
+    _playlist: OwnedMutexGuard<()>,
+    _permit: SemaphorePermit<'static>,
+}
+
+pub async fn acquire_write(service: &str, playlist_id: &str) -> Option<WriteGuard> {
+    let lock = {
+        let mut locks = match PLAYLIST_LOCKS.lock() {
+            Ok(l) => l,
+            Err(e) => e.into_inner(),
+        };
+
+        // Drop locks nobody is holding or waiting on.
+        locks.retain(|_, l| Arc::strong_count(l) > 1);
+
+        locks
+            .entry((service.to_string(), playlist_id.to_string()))
+            .or_default()
+            .clone()
+    };
+
+    let playlist = lock.lock_owned().await;
+    let permit = WRITE_PERMITS.get(service)?.acquire().await.ok()?;
+
+    Some(WriteGuard {
+        _playlist: playlist,
+        _permit: permit,
+    })
+}
+
+pub fn max_concurrent_writes() -> usize {
+    config::get().processing.max_concurrent_writes
+}
@@ -1,31 +1,54 @@
 mod auth;
+mod cards;
+mod clients;
+mod config;
+mod crypto;
 mod db;
+mod digest;
 mod discord;
+mod health;
+mod limits;
+mod matching;
+mod metrics;
+mod pages;
+mod ratelimit;
+mod settings;
 mod spotify;
+mod stats;
+mod supervisor;
+mod telemetry;
 mod tidal;
 mod youtube;
 
 use crate::auth::ExchangeToken;
-use crate::db::{get_auth_request_by_state, get_user_by_discord_user_id, insert_oauth_token};
-use crate::discord::Handler;
-use async_std::task;
+use crate::clients::AppClients;
+use crate::config::CliOverrides;
+use crate::db::{
+    AuthRequest, get_auth_request_by_state, get_user_by_discord_user_id, upsert_oauth_token,
+};
+use crate::discord::{AuthNotice, Handler};
+use crate::health::Readiness;
+use crate::pages::AuthFailure;
 use axum::extract::Query;
+use axum::response::Html;
 use axum::routing::get;
 use axum::{Router, extract::Form, extract::State};
 use clap::Parser;
 use http::StatusCode;
 use isopod::client::YoutubeClient;
-use log::{error, info};
 use prawn::client::TidalClient;
-use rspotify::{AuthCodeSpotify, ClientCredsSpotify, Credentials};
+use rspotify::AuthCodeSpotify;
 use rusqlite::Connection;
 use serde::Deserialize;
-use serenity::all::GatewayIntents;
-use std::env;
+use serenity::all::{GatewayIntents, Http};
 use std::error::Error;
 use std::fmt::Debug;
+use std::path::PathBuf;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -33,20 +56,59 @@ struct Args {
     // Only runs migrations if this is set.
     #[arg(short, long)]
     migrate: bool,
+
+    // Re-wraps stored secrets with TOKEN_ENCRYPTION_KEY, unwrapping with any key in
+    // TOKEN_ENCRYPTION_PREVIOUS_KEYS, then exits.
+    #[arg(long)]
+    rotate_encryption_key: bool,
+
+    // Path to a TOML config file. Falls back to SPOOTIFER_CONFIG, and env vars override
+    // values in the file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    #[arg(long)]
+    database_path: Option<String>,
+
+    #[arg(long)]
+    listen_addr: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
-    info!("starting spooty");
     let args = Args::parse();
-    let db_path: String = env::var("DATABASE_PATH").unwrap_or_else(|_| panic!("db path not set!"));
 
-    let discord_token: String = env::var("DISCORD_BOT_TOKEN").expect("DISCORD_BOT_TOKEN not set!");
+    let loaded = config::load(&CliOverrides {
+        config_path: args.config,
+        database_path: args.database_path,
+        listen_addr: args.listen_addr,
+    });
+
+    let telemetry = telemetry::init(
+        &loaded
+            .as_ref()
+            .map(|c| c.logging.clone())
+            .unwrap_or_default(),
+    );
+
+    metrics::init();
+    info!("starting spooty");
 
-    let mutex_conn = Mutex::new(Connection::open(db_path).unwrap());
+    let config = match loaded {
+        Ok(c) => config::init(c),
+        Err(e) => {
+            error!("{e}");
+            exit(1)
+        }
+    };
+
+    let mutex_conn = Mutex::new(Connection::open(config.database_path.as_str()).unwrap());
     info!("opened db connection");
 
+    if let Err(e) = crypto::cipher() {
+        panic!("token encryption not configured: {e}");
+    }
+
     if args.migrate {
         info!("migrating");
         db::run_migrations(mutex_conn)
@@ -54,44 +116,48 @@ async fn main() {
         exit(0)
     }
 
-    let conn = Arc::new(mutex_conn);
-
-    let credentials: Credentials = Credentials::from_env().expect("Spotify credentials not set!");
-
-    let spotify_client: ClientCredsSpotify = ClientCredsSpotify::new(credentials);
+    if args.rotate_encryption_key {
+        info!("rotating encryption key");
+        let rotated = db::rotate_encryption_key(mutex_conn)
+            .unwrap_or_else(|e| panic!("got error rotating encryption key: {e}"));
+        info!("re-wrapped {rotated} secrets");
+        exit(0)
+    }
 
-    spotify_client
-        .request_token()
-        .await
-        .expect("unable to fetch spotify token");
+    let conn = Arc::new(mutex_conn);
 
-    let tidal_client = match tidal::init_tidal_with_secret().await {
-        Ok(c) => c,
+    let clients = match AppClients::init().await {
+        Ok(c) => Arc::new(c),
         Err(e) => {
-            panic!("error initializing tidal client: {e}")
+            panic!("error initializing app clients: {e}")
         }
     };
 
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let refresher = supervisor::spawn_worker("client token refresher", shutdown_rx.clone(), {
+        let clients = clients.clone();
+        move || clients::keep_fresh(clients.clone())
+    });
+
+    // Tracks message handling so playlist writes can finish before the process exits.
+    let in_flight = TaskTracker::new();
+
     let handler = Handler {
         conn: conn.clone(),
-        spotify_client: Arc::new(spotify_client.clone()),
-        tidal_client: Arc::new(tidal_client.clone()),
+        clients: clients.clone(),
+        in_flight: in_flight.clone(),
     };
 
     let handler2 = Handler {
         conn: conn.clone(),
-        spotify_client: Arc::new(spotify_client.clone()),
-        tidal_client: Arc::new(tidal_client.clone()),
+        clients: clients.clone(),
+        in_flight: in_flight.clone(),
     };
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![
-                discord::register_playlist(),
-                discord::authorize_spotify(),
-                discord::authorize_tidal(),
-                discord::authorize_youtube(),
-            ],
+            commands: discord::commands(),
             ..Default::default()
         })
         .setup(move |ctx, _, framework| {
@@ -102,55 +168,148 @@ async fn main() {
         })
         .build();
 
-    let mut discord_client = serenity::Client::builder(&discord_token, GatewayIntents::all())
-        .framework(framework)
-        .event_handler(handler)
-        .await
-        .expect("Err creating discord client");
-
-    task::spawn(async move {
-        discord_client
-            .start()
+    let mut discord_client =
+        serenity::Client::builder(&config.discord.bot_token, GatewayIntents::all())
+            .framework(framework)
+            .event_handler(handler)
             .await
-            .expect("Err connecting to discord");
-        info!("started discord bot");
+            .expect("Err creating discord client");
+
+    let discord_http = discord_client.http.clone();
+    let shard_manager = discord_client.shard_manager.clone();
+
+    let digests = supervisor::spawn_worker("weekly digest", shutdown_rx.clone(), {
+        let conn = conn.clone();
+        let http = discord_http.clone();
+        move || digest::run(conn.clone(), http.clone())
     });
 
-    start_auth_server(conn.clone())
-        .await
-        .expect("Err starting auth server");
+    let readiness = Readiness {
+        conn: conn.clone(),
+        shard_manager: shard_manager.clone(),
+        clients,
+    };
+
+    let mut gateway = tokio::spawn(async move {
+        info!("starting discord bot");
+        discord_client.start().await
+    });
+
+    let mut server = tokio::spawn(start_auth_server(
+        conn.clone(),
+        discord_http,
+        readiness,
+        shutdown_rx,
+    ));
+
+    let failed = tokio::select! {
+        () = supervisor::shutdown_signal() => false,
+        r = &mut gateway => {
+            match r {
+                Ok(Ok(())) => error!("discord client stopped"),
+                Ok(Err(e)) => error!("discord client failed: {e}"),
+                Err(e) => error!("discord client task failed: {e}"),
+            }
+            true
+        }
+        r = &mut server => {
+            match r {
+                Ok(Ok(())) => error!("auth server stopped"),
+                Ok(Err(e)) => error!("auth server failed: {e}"),
+                Err(e) => error!("auth server task failed: {e}"),
+            }
+            true
+        }
+    };
+
+    info!("shutting down");
+
+    shard_manager.shutdown_all().await;
+    supervisor::drain(&in_flight).await;
+
+    _ = shutdown_tx.send(true);
+
+    if !server.is_finished() {
+        _ = server.await;
+    }
+
+    if !gateway.is_finished() {
+        gateway.abort();
+    }
+
+    _ = refresher.await;
+    _ = digests.await;
+
+    info!("shut down");
+
+    drop(telemetry);
+
+    if failed {
+        exit(1)
+    }
 }
 
 struct ServerState {
     conn: Arc<Mutex<Connection>>,
+    discord_http: Arc<Http>,
 }
 
-async fn start_auth_server(conn: Arc<Mutex<Connection>>) -> Result<(), Box<dyn Error>> {
-    let server = ServerState { conn };
+async fn start_auth_server(
+    conn: Arc<Mutex<Connection>>,
+    discord_http: Arc<Http>,
+    readiness: Readiness,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let server = ServerState { conn, discord_http };
 
     let shared_state = Arc::new(server);
 
-    let app = Router::new().route("/callback", get(complete_auth).with_state(shared_state));
+    let app = Router::new()
+        .route("/callback", get(complete_auth).with_state(shared_state))
+        .route("/healthz", get(health::healthz));
 
-    let listener = match tokio::net::TcpListener::bind("0.0.0.0:8081").await {
-        Ok(l) => l,
-        Err(e) => {
-            panic!("Error starting server {e}");
-        }
-    };
+    // Readiness and metrics expose internal state, so they are only served on the
+    // internal address, which is not published as a public service.
+    let internal = Router::new()
+        .route("/healthz", get(health::healthz))
+        .route(
+            "/readyz",
+            get(health::readyz).with_state(Arc::new(readiness)),
+        )
+        .route("/metrics", get(health::export_metrics));
+
+    let config = config::get();
+    let listener = tokio::net::TcpListener::bind(config.server.listen_addr.as_str()).await?;
+    let internal_listener =
+        tokio::net::TcpListener::bind(config.server.internal_listen_addr.as_str()).await?;
+
+    let mut internal_shutdown = shutdown.clone();
 
     info!("started auth listener");
-    match axum::serve(listener, app).await {
-        Ok(()) => Ok(()),
-        Err(e) => panic!("oh no! couldn't start server: {e}"),
-    }
+    info!("started internal listener");
+    tokio::try_join!(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                _ = shutdown.wait_for(|s| *s).await;
+                info!("stopping auth listener");
+            })
+            .into_future(),
+        axum::serve(internal_listener, internal)
+            .with_graceful_shutdown(async move {
+                _ = internal_shutdown.wait_for(|s| *s).await;
+                info!("stopping internal listener");
+            })
+            .into_future(),
+    )?;
+
+    Ok(())
 }
 
 async fn complete_auth(
     State(state): State<Arc<ServerState>>,
     code: Query<Code>,
     complete_auth_request: Form<CompleteAuthRequest>,
-) -> (StatusCode, String) {
+) -> (StatusCode, Html<String>) {
     let auth_request = match get_auth_request_by_state(
         &state.conn,
         complete_auth_request.state.clone().as_str(),
@@ -158,59 +317,67 @@ async fn complete_auth(
         Ok(a) => a,
         Err(e) => {
             error!("error fetching auth request: {e}");
-            return (
-                StatusCode::UNAUTHORIZED,
-                "Unauthorized: auth request not found".to_string(),
-            );
+            return pages::auth_failure(None, AuthFailure::RequestNotFound);
         }
     };
 
+    let notice = AuthNotice::from(&auth_request);
+    let service = notice.service.clone();
+    let guild_name = notice.guild_name.clone();
+
+    let result = store_oauth_token(&state, auth_request, code.code.clone()).await;
+
+    discord::notify_auth_result(&state.discord_http, &state.conn, notice, result.is_ok()).await;
+
+    match result {
+        Ok(()) => pages::auth_success(service.as_str(), guild_name.as_deref()),
+        Err(AuthFailure::UnknownService) => pages::auth_failure(None, AuthFailure::UnknownService),
+        Err(f) => pages::auth_failure(Some(service.as_str()), f),
+    }
+}
+
+async fn store_oauth_token(
+    state: &ServerState,
+    auth_request: AuthRequest,
+    code: String,
+) -> Result<(), AuthFailure> {
     let user = match get_user_by_discord_user_id(&state.conn, auth_request.discord_user_id.as_str())
     {
         Ok(u) => u,
         Err(e) => {
             error!("failed to get user: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            );
+            return Err(AuthFailure::Internal);
         }
     };
 
     let Some(user_id) = user.id else {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "user id missing".to_string(),
-        );
+        error!("user id missing");
+        return Err(AuthFailure::Internal);
     };
 
     let maybe_oauth_token = match auth_request.for_service.as_str() {
-        "spotify" => {
-            AuthCodeSpotify::exchange_token(auth_request, code.code.clone(), user_id).await
-        }
-        "tidal" => TidalClient::exchange_token(auth_request, code.code.clone(), user_id).await,
-        "youtube" => YoutubeClient::exchange_token(auth_request, code.code.clone(), user_id).await,
-        _ => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "bad service name".to_string(),
-            );
+        "spotify" => AuthCodeSpotify::exchange_token(auth_request, code, user_id).await,
+        "tidal" => TidalClient::exchange_token(auth_request, code, user_id).await,
+        "youtube" => YoutubeClient::exchange_token(auth_request, code, user_id).await,
+        s => {
+            error!("auth request for unknown service {s}");
+            return Err(AuthFailure::UnknownService);
         }
     };
 
     let auth_token = match maybe_oauth_token {
         Ok(o) => o,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}")),
+        Err(e) => {
+            error!("error exchanging token: {e}");
+            return Err(AuthFailure::ExchangeFailed);
+        }
     };
 
     let mut conn = match state.conn.try_lock() {
         Ok(c) => c,
         Err(e) => {
             error!("error locking: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            );
+            return Err(AuthFailure::Internal);
         }
     };
 
@@ -218,34 +385,23 @@ async fn complete_auth(
         Ok(t) => t,
         Err(e) => {
             error!("Error opening transaction: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            );
+            return Err(AuthFailure::Internal);
         }
     };
 
-    let commit_result = match insert_oauth_token(&tx, auth_token) {
+    let commit_result = match upsert_oauth_token(&tx, auth_token) {
         Ok(_) => tx.commit(),
         Err(e) => {
             error!("Error creating auth token: {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            );
+            return Err(AuthFailure::Internal);
         }
     };
 
-    drop(conn);
-
     match commit_result {
-        Ok(()) => (StatusCode::OK, "Authorized!".to_string()),
+        Ok(()) => Ok(()),
         Err(e) => {
             error!("Error committing transaction: {e}");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            )
+            Err(AuthFailure::Internal)
         }
     }
 }
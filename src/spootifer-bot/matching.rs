@@ -0,0 +1,192 @@
+use crate::db::{MatchOverride, get_match_override};
+use crate::pages;
+use crate::spotify::{self, IdType};
+use crate::tidal::{self, TidalResource};
+use rusqlite::Connection;
+use serenity::all::MessageBuilder;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Why a resource could not be matched on another service. Ordered from least to most
+/// specific so the most useful explanation wins when several searches were tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchFailureReason {
+    LookupFailed,
+    NoSearchResults,
+    BelowThreshold,
+    IsrcMismatch,
+}
+
+impl Display for MatchFailureReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LookupFailed => write!(f, "the lookup failed"),
+            Self::NoSearchResults => write!(f, "the search returned no results"),
+            Self::BelowThreshold => write!(f, "no search result was a close enough match"),
+            Self::IsrcMismatch => {
+                write!(f, "a result had the same title but a different ISRC")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchFailure {
+    pub from: &'static str,
+    pub to: &'static str,
+    // What was being matched, e.g. "Blue Lines by Massive Attack".
+    pub description: String,
+    pub reason: MatchFailureReason,
+}
+
+impl Display for MatchFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Titles come from the services as-is, so they're escaped before going in bold.
+        let description = MessageBuilder::new()
+            .push_bold_safe(self.description.as_str())
+            .build();
+
+        write!(
+            f,
+            "{description} on {}: {}",
+            pages::service_display_name(self.to),
+            self.reason
+        )
+    }
+}
+
+pub fn describe(name: &str, artist: Option<&str>) -> String {
+    match artist {
+        Some(a) if !a.is_empty() => format!("{name} by {a}"),
+        _ => name.to_string(),
+    }
+}
+
+/// A single album or track on a service that can be matched, as stored in
+/// `match_overrides`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceKey {
+    pub service: &'static str,
+    pub kind: &'static str,
+    pub id: String,
+}
+
+impl From<&IdType> for ResourceKey {
+    fn from(value: &IdType) -> Self {
+        let (kind, id) = match value {
+            IdType::Album(id) => ("album", id),
+            IdType::Track(id) => ("track", id),
+        };
+
+        Self {
+            service: "spotify",
+            kind,
+            id: id.clone(),
+        }
+    }
+}
+
+impl From<&TidalResource> for ResourceKey {
+    fn from(value: &TidalResource) -> Self {
+        let (kind, id) = match value {
+            TidalResource::Album(id) => ("album", id),
+            TidalResource::Track(id) => ("track", id),
+        };
+
+        Self {
+            service: "tidal",
+            kind,
+            id: id.clone(),
+        }
+    }
+}
+
+impl ResourceKey {
+    /// Parses the first Spotify or Tidal album or track in `link`.
+    pub fn from_link(link: &str) -> Option<Self> {
+        if spotify::contains_spotify_link(link) {
+            return spotify::extract_ids(link).first().map(Self::from);
+        }
+
+        if tidal::contains_tidal_link(link) {
+            return tidal::extract_ids(link).first().map(Self::from);
+        }
+
+        None
+    }
+
+    pub fn to_spotify(&self) -> Option<IdType> {
+        if self.service != "spotify" {
+            return None;
+        }
+
+        spotify_id(self.kind, self.id.clone())
+    }
+
+    pub fn to_tidal(&self) -> Option<TidalResource> {
+        if self.service != "tidal" {
+            return None;
+        }
+
+        tidal_resource(self.kind, self.id.clone())
+    }
+}
+
+fn spotify_id(kind: &str, id: String) -> Option<IdType> {
+    match kind {
+        "album" => Some(IdType::Album(id)),
+        "track" => Some(IdType::Track(id)),
+        _ => None,
+    }
+}
+
+fn tidal_resource(kind: &str, id: String) -> Option<TidalResource> {
+    match kind {
+        "album" => Some(TidalResource::Album(id)),
+        "track" => Some(TidalResource::Track(id)),
+        _ => None,
+    }
+}
+
+// A failed lookup is returned rather than treated as no override, so a saved fix is
+// never silently replaced by the automatic matcher.
+fn find_override(
+    conn: &Arc<Mutex<Connection>>,
+    source: &ResourceKey,
+    target_service: &str,
+) -> Result<Option<MatchOverride>> {
+    let o = match get_match_override(conn, source.service, source.id.as_str(), target_service) {
+        Ok(Some(o)) => o,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            error!("failed to look up match override: {e}");
+            return Err(e.to_string().into());
+        }
+    };
+
+    info!(
+        "using match override {}:{} -> {}:{}",
+        o.source_service, o.source_id, o.target_service, o.target_id
+    );
+
+    Ok(Some(o))
+}
+
+/// The Tidal resource manually matched to a Spotify id with `/fix_match`, if any.
+pub fn tidal_override(conn: &Arc<Mutex<Connection>>, id: &IdType) -> Result<Option<TidalResource>> {
+    Ok(find_override(conn, &ResourceKey::from(id), "tidal")?
+        .and_then(|o| tidal_resource(o.target_kind.as_str(), o.target_id)))
+}
+
+/// The Spotify id manually matched to a Tidal resource with `/fix_match`, if any.
+pub fn spotify_override(
+    conn: &Arc<Mutex<Connection>>,
+    id: &TidalResource,
+) -> Result<Option<IdType>> {
+    Ok(find_override(conn, &ResourceKey::from(id), "spotify")?
+        .and_then(|o| spotify_id(o.target_kind.as_str(), o.target_id)))
+}
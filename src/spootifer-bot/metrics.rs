@@ -0,0 +1,184 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::error;
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static MESSAGES_PROCESSED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "spootifer_messages_processed_total",
+            "Discord messages processed by the link handler",
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static LINKS_EXTRACTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "spootifer_links_extracted_total",
+                "Links extracted from Discord messages",
+            ),
+            &["service"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static MATCHES_ATTEMPTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "spootifer_matches_attempted_total",
+                "Attempts to match a resource on another service",
+            ),
+            &["from", "to"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static MATCHES_SUCCEEDED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "spootifer_matches_succeeded_total",
+                "Resources successfully matched on another service",
+            ),
+            &["from", "to"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static PLAYLIST_ADDS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "spootifer_playlist_adds_total",
+                "Requests adding items to a user's playlist",
+            ),
+            &["service", "outcome"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static API_LATENCY: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "spootifer_api_request_duration_seconds",
+                "Latency of requests to music service APIs",
+            ),
+            &["service", "operation"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static THROTTLED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "spootifer_throttled_total",
+                "Requests delayed by the local rate limiter or a 429 from the service",
+            ),
+            &["service", "reason"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+static THROTTLE_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "spootifer_throttle_wait_seconds",
+                "Time requests spent waiting on rate limits",
+            ),
+            &["service", "reason"],
+        )
+        .expect("invalid metric"),
+    )
+});
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+
+    metric
+}
+
+/// Registers every metric up front so they are exported before their first use.
+pub fn init() {
+    LazyLock::force(&MESSAGES_PROCESSED);
+    LazyLock::force(&LINKS_EXTRACTED);
+    LazyLock::force(&MATCHES_ATTEMPTED);
+    LazyLock::force(&MATCHES_SUCCEEDED);
+    LazyLock::force(&PLAYLIST_ADDS);
+    LazyLock::force(&API_LATENCY);
+    LazyLock::force(&THROTTLED);
+    LazyLock::force(&THROTTLE_WAIT);
+}
+
+pub fn record_message_processed() {
+    MESSAGES_PROCESSED.inc();
+}
+
+pub fn record_links_extracted(service: &str, count: usize) {
+    LINKS_EXTRACTED
+        .with_label_values(&[service])
+        .inc_by(count as u64);
+}
+
+pub fn record_match(from: &str, to: &str, succeeded: bool) {
+    MATCHES_ATTEMPTED.with_label_values(&[from, to]).inc();
+
+    if succeeded {
+        MATCHES_SUCCEEDED.with_label_values(&[from, to]).inc();
+    }
+}
+
+pub fn record_playlist_add(service: &str, succeeded: bool) {
+    let outcome = if succeeded { "success" } else { "failure" };
+
+    PLAYLIST_ADDS.with_label_values(&[service, outcome]).inc();
+}
+
+pub fn record_throttled(service: &str, reason: &str, wait: Duration) {
+    THROTTLED.with_label_values(&[service, reason]).inc();
+    THROTTLE_WAIT
+        .with_label_values(&[service, reason])
+        .observe(wait.as_secs_f64());
+}
+
+/// Awaits `f`, recording how long it took in the API latency histogram.
+pub async fn timed<F: Future>(service: &str, operation: &str, f: F) -> F::Output {
+    let timer = API_LATENCY
+        .with_label_values(&[service, operation])
+        .start_timer();
+
+    let output = f.await;
+
+    timer.observe_duration();
+
+    output
+}
+
+pub fn gather() -> String {
+    let mut buffer = vec![];
+
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        error!("failed to encode metrics: {e}");
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1 @@
+ALTER TABLE "users" ADD COLUMN opt_in_only integer NOT NULL DEFAULT 0;
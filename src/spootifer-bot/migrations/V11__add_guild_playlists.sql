@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS "guild_playlists" (
+    `id` integer,
+    `created_at` text,
+    `updated_at` text,
+    `deleted_at` text,
+    `discord_guild_id` text,
+    `for_service` text,
+    `playlist_id` text,
+    `owner_user_id` integer,
+    PRIMARY KEY (`id`),
+    CONSTRAINT `fk_users_guild_playlists` FOREIGN KEY (`owner_user_id`) REFERENCES `users`(`id`)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS `idx_guild_playlists_guild_id_for_service` ON `guild_playlists`(`discord_guild_id`, `for_service`);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS "guild_settings" (
+    `id` integer,
+    `created_at` text,
+    `updated_at` text,
+    `discord_guild_id` text,
+    `spotify_reaction` text,
+    `tidal_reaction` text,
+    `youtube_reaction` text,
+    `album_art` integer,
+    `expand_albums` integer,
+    PRIMARY KEY (`id`)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS `idx_guild_settings_guild_id` ON `guild_settings`(`discord_guild_id`);
@@ -0,0 +1,13 @@
+ALTER TABLE "guild_settings" ADD COLUMN ignore_bots integer;
+
+CREATE TABLE IF NOT EXISTS "guild_channel_rules" (
+    `id` integer,
+    `created_at` text,
+    `updated_at` text,
+    `discord_guild_id` text,
+    `channel_id` text,
+    `allow` integer,
+    PRIMARY KEY (`id`)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS `idx_guild_channel_rules_guild_id_channel_id` ON `guild_channel_rules`(`discord_guild_id`, `channel_id`);
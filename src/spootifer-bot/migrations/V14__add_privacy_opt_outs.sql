@@ -0,0 +1,10 @@
+-- An empty discord_guild_id opts the user out in every guild.
+CREATE TABLE IF NOT EXISTS "privacy_opt_outs" (
+    `id` integer,
+    `created_at` text,
+    `discord_user_id` text NOT NULL,
+    `discord_guild_id` text NOT NULL DEFAULT '',
+    PRIMARY KEY (`id`)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS `idx_privacy_opt_outs_user_id_guild_id` ON `privacy_opt_outs`(`discord_user_id`, `discord_guild_id`);
@@ -0,0 +1,10 @@
+ALTER TABLE "message_links" ADD COLUMN author_discord_user_id text;
+ALTER TABLE "message_links" ADD COLUMN service text;
+ALTER TABLE "message_links" ADD COLUMN title text;
+ALTER TABLE "message_links" ADD COLUMN artist text;
+ALTER TABLE "message_links" ADD COLUMN album text;
+-- Comma separated, as Spotify gives them for albums.
+ALTER TABLE "message_links" ADD COLUMN genres text;
+ALTER TABLE "message_links" ADD COLUMN track_count integer;
+
+CREATE INDEX IF NOT EXISTS `idx_message_links_guild_id_created_at` ON `message_links`(`guild_id`, `created_at`);
@@ -0,0 +1,3 @@
+ALTER TABLE "guild_settings" ADD COLUMN digest_channel_id text;
+-- Only written by the digest task, never by settings changes.
+ALTER TABLE "guild_settings" ADD COLUMN digest_sent_at text;
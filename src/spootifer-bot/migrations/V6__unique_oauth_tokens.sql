@@ -0,0 +1,23 @@
+CREATE TABLE IF NOT EXISTS "oauth_token_history" (
+    `id` integer,
+    `created_at` text,
+    `updated_at` text,
+    `deleted_at` text,
+    `user_id` integer,
+    `refresh_token` text,
+    `access_token` text,
+    `expiry_time` text,
+    `token_type` text,
+    `for_service` text,
+    PRIMARY KEY (`id`),
+    CONSTRAINT `fk_users_oauth_token_history` FOREIGN KEY (`user_id`) REFERENCES `users`(`id`)
+);
+
+CREATE INDEX IF NOT EXISTS `idx_oauth_token_history_user_id_for_service` ON `oauth_token_history`(`user_id`, `for_service`);
+
+INSERT INTO "oauth_token_history" (created_at, updated_at, deleted_at, user_id, refresh_token, access_token, expiry_time, token_type, for_service)
+    SELECT created_at, updated_at, deleted_at, user_id, refresh_token, access_token, expiry_time, token_type, for_service FROM "oauth_tokens" ORDER BY id;
+
+DELETE FROM "oauth_tokens" WHERE id NOT IN (SELECT MAX(id) FROM "oauth_tokens" GROUP BY user_id, for_service);
+
+CREATE UNIQUE INDEX IF NOT EXISTS `idx_oauth_tokens_user_id_for_service` ON `oauth_tokens`(`user_id`, `for_service`);
@@ -0,0 +1,2 @@
+ALTER TABLE "auth_requests" ADD COLUMN discord_guild_id TEXT;
+ALTER TABLE "auth_requests" ADD COLUMN discord_guild_name TEXT;
@@ -0,0 +1 @@
+ALTER TABLE "auth_requests" ADD COLUMN interaction_token TEXT;
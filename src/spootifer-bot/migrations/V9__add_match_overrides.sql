@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS "match_overrides" (
+    `id` integer,
+    `created_at` text,
+    `updated_at` text,
+    `deleted_at` text,
+    `source_service` text,
+    `source_kind` text,
+    `source_id` text,
+    `target_service` text,
+    `target_kind` text,
+    `target_id` text,
+    `created_by_user_id` integer,
+    PRIMARY KEY (`id`),
+    CONSTRAINT `fk_users_match_overrides` FOREIGN KEY (`created_by_user_id`) REFERENCES `users`(`id`)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS `idx_match_overrides_source_target` ON `match_overrides`(`source_service`, `source_id`, `target_service`);
@@ -0,0 +1,151 @@
+use axum::response::Html;
+use http::StatusCode;
+
+static AUTH_RESULT_TEMPLATE: &str = include_str!("templates/auth_result.html");
+
+/// Failures shown to users on the callback page. These deliberately say nothing
+/// about the underlying error, which is logged instead.
+#[derive(Debug, Clone, Copy)]
+pub enum AuthFailure {
+    RequestNotFound,
+    UnknownService,
+    ExchangeFailed,
+    Internal,
+}
+
+impl AuthFailure {
+    const fn status(self) -> StatusCode {
+        match self {
+            Self::RequestNotFound => StatusCode::UNAUTHORIZED,
+            Self::UnknownService => StatusCode::BAD_REQUEST,
+            Self::ExchangeFailed | Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    const fn message(self) -> &'static str {
+        match self {
+            Self::RequestNotFound => {
+                "We couldn't find the authorization request for this link. It may have already been used."
+            }
+            Self::UnknownService => "This link was for a service Spootifer doesn't support.",
+            Self::ExchangeFailed => {
+                "The music service didn't accept the authorization. Please try again."
+            }
+            Self::Internal => "Something went wrong on our end while saving your authorization.",
+        }
+    }
+}
+
+pub fn service_display_name(service: &str) -> &str {
+    match service {
+        "spotify" => "Spotify",
+        "tidal" => "TIDAL",
+        "youtube" => "YouTube",
+        _ => service,
+    }
+}
+
+fn accent(service: &str) -> &'static str {
+    match service {
+        "spotify" => "#1ed760",
+        "tidal" => "#33ffee",
+        "youtube" => "#ff3333",
+        _ => "#5865f2",
+    }
+}
+
+pub fn auth_success(service: &str, guild_name: Option<&str>) -> (StatusCode, Html<String>) {
+    let server = guild_name.map_or_else(
+        || String::from("your Discord server"),
+        |n| format!("<strong>{}</strong>", escape(n)),
+    );
+
+    (
+        StatusCode::OK,
+        render(
+            service,
+            "You're authorized!",
+            format!(
+                "Spootifer can now add links posted in {server} to your {} playlists.",
+                escape(service_display_name(service))
+            )
+            .as_str(),
+            format!(
+                "Head back to Discord and run <code>/register_playlist</code> with a link to the {} playlist you want to archive to. You can close this page.",
+                escape(service_display_name(service))
+            )
+            .as_str(),
+        ),
+    )
+}
+
+pub fn auth_failure(service: Option<&str>, failure: AuthFailure) -> (StatusCode, Html<String>) {
+    let next_steps = match service {
+        Some(s @ ("spotify" | "tidal" | "youtube")) => {
+            format!("Run <code>/authorize_{s}</code> in Discord again to get a fresh link.")
+        }
+        _ => String::from(
+            "Run the authorize command for your service in Discord again to get a fresh link.",
+        ),
+    };
+
+    (
+        failure.status(),
+        render(
+            service.unwrap_or("spootifer"),
+            "Authorization failed",
+            failure.message(),
+            next_steps.as_str(),
+        ),
+    )
+}
+
+// `message` and `next_steps` are inserted as-is, so callers must escape anything
+// that did not come from this module.
+fn render(service: &str, title: &str, message: &str, next_steps: &str) -> Html<String> {
+    let service_name = escape(service_display_name(service));
+    let title = escape(title);
+    let values = [
+        ("accent", accent(service)),
+        ("service", service_name.as_str()),
+        ("title", title.as_str()),
+        ("message", message),
+        ("next_steps", next_steps),
+    ];
+
+    Html(substitute(AUTH_RESULT_TEMPLATE, &values))
+}
+
+// Fills `{{key}}` placeholders in a single pass over the template, so values are
+// never themselves searched for placeholders. Unknown keys are left untouched.
+fn substitute(template: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+
+        let key = &after[..end];
+        match values.iter().find(|(k, _)| *k == key) {
+            Some((_, value)) => out.push_str(value),
+            None => out.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,269 @@
+use crate::metrics;
+use rspotify::ClientError;
+use rspotify::http::HttpError;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const MAX_RETRIES: u32 = 3;
+// Used when a 429 does not say how long to wait, doubled on every retry.
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(2);
+// Buckets untouched for this long are full again and can be dropped.
+const IDLE_BUCKET: Duration = Duration::from_secs(600);
+// Waits shorter than this are routine and not worth logging.
+const LOG_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+struct Limit {
+    per_second: f64,
+    burst: f64,
+}
+
+// Shared by every request to a service, whichever token it uses.
+fn service_limit(service: &str) -> Limit {
+    match service {
+        "spotify" => Limit {
+            per_second: 10.0,
+            burst: 20.0,
+        },
+        "tidal" => Limit {
+            per_second: 4.0,
+            burst: 8.0,
+        },
+        _ => Limit {
+            per_second: 5.0,
+            burst: 10.0,
+        },
+    }
+}
+
+// Applied on top of the service limit to requests made with a user's token.
+const USER_LIMIT: Limit = Limit {
+    per_second: 2.0,
+    burst: 5.0,
+};
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+    paused_until: Option<Instant>,
+}
+
+impl Bucket {
+    const fn new(limit: Limit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+            paused_until: None,
+        }
+    }
+
+    /// Refills the bucket and returns how long until a token is available.
+    fn wait(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = elapsed
+            .mul_add(self.limit.per_second, self.tokens)
+            .min(self.limit.burst);
+        self.updated = now;
+
+        if let Some(until) = self.paused_until {
+            if until > now {
+                return until - now;
+            }
+
+            self.paused_until = None;
+        }
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.limit.per_second)
+        }
+    }
+}
+
+static BUCKETS: LazyLock<Mutex<HashMap<String, Bucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn bucket_key(service: &str, user: Option<i64>) -> String {
+    user.map_or_else(|| service.to_string(), |u| format!("{service}:{u}"))
+}
+
+/// Waits until both the service's bucket and, if given, the user's bucket have a token,
+/// then takes one from each.
+pub async fn acquire(service: &str, user: Option<i64>) {
+    let mut waited = Duration::ZERO;
+
+    loop {
+        let wait = {
+            let mut buckets = match BUCKETS.lock() {
+                Ok(b) => b,
+                Err(e) => e.into_inner(),
+            };
+
+            let now = Instant::now();
+
+            buckets.retain(|_, b| now.saturating_duration_since(b.updated) < IDLE_BUCKET);
+
+            let mut keys = vec![(bucket_key(service, None), service_limit(service))];
+            if user.is_some() {
+                keys.push((bucket_key(service, user), USER_LIMIT));
+            }
+
+            let wait = keys
+                .iter()
+                .map(|(key, limit)| {
+                    buckets
+                        .entry(key.clone())
+                        .or_insert_with(|| Bucket::new(*limit, now))
+                        .wait(now)
+                })
+                .max()
+                .unwrap_or_default();
+
+            if wait.is_zero() {
+                for (key, _) in &keys {
+                    if let Some(b) = buckets.get_mut(key) {
+                        b.tokens -= 1.0;
+                    }
+                }
+            }
+
+            wait
+        };
+
+        if wait.is_zero() {
+            break;
+        }
+
+        waited += wait;
+        tokio::time::sleep(wait).await;
+    }
+
+    if !waited.is_zero() {
+        metrics::record_throttled(service, "limiter", waited);
+
+        if waited >= LOG_WAIT {
+            info!("throttled {service} request for {waited:?}");
+        }
+    }
+}
+
+/// Stops every request to `service` until `wait` has passed.
+fn pause(service: &str, wait: Duration) {
+    let mut buckets = match BUCKETS.lock() {
+        Ok(b) => b,
+        Err(e) => e.into_inner(),
+    };
+
+    let now = Instant::now();
+    let until = now + wait;
+
+    let bucket = buckets
+        .entry(bucket_key(service, None))
+        .or_insert_with(|| Bucket::new(service_limit(service), now));
+
+    bucket.paused_until = Some(bucket.paused_until.map_or(until, |u| u.max(until)));
+}
+
+/// Errors from a service client that can tell whether the request was rejected
+/// with a 429.
+pub trait RateLimitError {
+    /// Returns `Some` if this is a 429, holding the `Retry-After` delay when the
+    /// service gave one.
+    fn rate_limited(&self) -> Option<Option<Duration>>;
+}
+
+impl RateLimitError for ClientError {
+    fn rate_limited(&self) -> Option<Option<Duration>> {
+        let ClientError::Http(http) = self else {
+            return None;
+        };
+
+        let HttpError::StatusCode(response) = http.as_ref() else {
+            return None;
+        };
+
+        if response.status().as_u16() != 429 {
+            return None;
+        }
+
+        Some(
+            response
+                .headers()
+                .get("retry-after")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map(Duration::from_secs),
+        )
+    }
+}
+
+// The generated Tidal and YouTube clients keep the status but not the headers, so
+// their 429s always fall back to the default backoff.
+impl<T> RateLimitError for prawn::apis::Error<T> {
+    fn rate_limited(&self) -> Option<Option<Duration>> {
+        match self {
+            prawn::apis::Error::ResponseError(r) if r.status.as_u16() == 429 => Some(None),
+            _ => None,
+        }
+    }
+}
+
+impl<T> RateLimitError for isopod::apis::Error<T> {
+    fn rate_limited(&self) -> Option<Option<Duration>> {
+        match self {
+            isopod::apis::Error::ResponseError(r) if r.status.as_u16() == 429 => Some(None),
+            _ => None,
+        }
+    }
+}
+
+/// Runs a request to `service` once the rate limiter allows it, recording its latency.
+/// Requests rejected with a 429 pause the whole service for the `Retry-After` delay and
+/// are retried a few times. `f` is called again for each attempt.
+pub async fn call<T, E, F, Fut>(
+    service: &str,
+    operation: &str,
+    user: Option<i64>,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: RateLimitError,
+{
+    let mut attempt = 0;
+
+    loop {
+        acquire(service, user).await;
+
+        let result = metrics::timed(service, operation, f()).await;
+
+        let limited = match &result {
+            Ok(_) => None,
+            Err(e) => e.rate_limited(),
+        };
+
+        let Some(retry_after) = limited else {
+            return result;
+        };
+
+        let wait = retry_after.unwrap_or(DEFAULT_BACKOFF * 2u32.pow(attempt));
+
+        metrics::record_throttled(service, "rate_limited", wait);
+        pause(service, wait);
+
+        if attempt >= MAX_RETRIES {
+            warn!("{service} rate limited {operation} after {attempt} retries, giving up");
+            return result;
+        }
+
+        warn!("{service} rate limited {operation}, retrying in {wait:?}");
+        attempt += 1;
+    }
+}
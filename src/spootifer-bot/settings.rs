@@ -0,0 +1,251 @@
+use crate::db::{
+    ChannelRule, GuildSettings, get_channel_rules_by_guild_id, get_guild_settings_by_guild_id,
+    upsert_guild_settings,
+};
+use rusqlite::Connection;
+use serenity::all::{ChannelId, ReactionType};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+const DEFAULT_SPOTIFY_REACTION: &str = "✅";
+const DEFAULT_TIDAL_REACTION: &str = "🌊";
+const DEFAULT_YOUTUBE_REACTION: &str = "🏮";
+
+/// A guild's settings with defaults filled in, loaded for every message.
+#[derive(Clone)]
+pub struct Settings {
+    stored: GuildSettings,
+    // `None` if the rules couldn't be read, in which case no channel is watched.
+    channel_rules: Option<Vec<ChannelRule>>,
+}
+
+impl Settings {
+    /// The emoji the bot reacts with once a message's links for `service` were added.
+    pub fn reaction(&self, service: &str) -> ReactionType {
+        let (custom, default) = match service {
+            "spotify" => (&self.stored.spotify_reaction, DEFAULT_SPOTIFY_REACTION),
+            "tidal" => (&self.stored.tidal_reaction, DEFAULT_TIDAL_REACTION),
+            _ => (&self.stored.youtube_reaction, DEFAULT_YOUTUBE_REACTION),
+        };
+
+        custom
+            .as_deref()
+            .and_then(|r| ReactionType::try_from(r).ok())
+            .unwrap_or_else(|| ReactionType::Unicode(String::from(default)))
+    }
+
+    /// Whether messages with links get a card with the item's art and links replied.
+    pub fn album_art(&self) -> bool {
+        self.stored.album_art.unwrap_or(true)
+    }
+
+    /// Whether album links add every track on the album, rather than being skipped.
+    pub fn expand_albums(&self) -> bool {
+        self.stored.expand_albums.unwrap_or(true)
+    }
+
+    /// Whether messages from bots and webhooks are skipped.
+    pub fn ignore_bots(&self) -> bool {
+        self.stored.ignore_bots.unwrap_or(true)
+    }
+
+    /// The channel the weekly digest is posted to, if the guild wants one.
+    pub fn digest_channel(&self) -> Option<&str> {
+        self.stored.digest_channel_id.as_deref()
+    }
+
+    pub fn has_channel_rules(&self) -> bool {
+        self.channel_rules.as_ref().is_none_or(|r| !r.is_empty())
+    }
+
+    /// Whether links are picked up from a channel, given it and the channels it sits
+    /// under. A deny anywhere wins, and once anything is allowed only allowed channels
+    /// are watched. Nothing is watched if the rules couldn't be read.
+    pub fn watches(&self, lineage: &[ChannelId]) -> bool {
+        let Some(rules) = &self.channel_rules else {
+            return false;
+        };
+
+        let matching = |allow: bool| {
+            rules
+                .iter()
+                .any(|r| r.allow == allow && lineage.iter().any(|c| c.to_string() == r.channel_id))
+        };
+
+        if matching(false) {
+            return false;
+        }
+
+        !rules.iter().any(|r| r.allow) || matching(true)
+    }
+
+    /// Renders the settings for `/settings show`.
+    pub fn describe(&self) -> String {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        let channels = |allow: bool| {
+            let Some(rules) = &self.channel_rules else {
+                return String::from("couldn't be loaded");
+            };
+
+            let mentions: Vec<String> = rules
+                .iter()
+                .filter(|r| r.allow == allow)
+                .map(|r| format!("<#{}>", r.channel_id))
+                .collect();
+
+            if mentions.is_empty() {
+                String::from("none")
+            } else {
+                mentions.join(", ")
+            }
+        };
+
+        format!(
+            "Spotify reaction: {}\nTIDAL reaction: {}\nYouTube reaction: {}\nAlbum art: {}\nExpand albums: {}\nIgnore bots and webhooks: {}\nWeekly digest: {}\nAllowed channels: {}\nDenied channels: {}",
+            reaction_display(&self.reaction("spotify")),
+            reaction_display(&self.reaction("tidal")),
+            reaction_display(&self.reaction("youtube")),
+            on_off(self.album_art()),
+            on_off(self.expand_albums()),
+            on_off(self.ignore_bots()),
+            self.digest_channel()
+                .map_or_else(|| String::from("off"), |c| format!("<#{c}>")),
+            channels(true),
+            channels(false),
+        )
+    }
+}
+
+fn reaction_display(reaction: &ReactionType) -> String {
+    match reaction {
+        ReactionType::Unicode(u) => u.clone(),
+        r => r.to_string(),
+    }
+}
+
+fn defaults(guild_id: &str) -> GuildSettings {
+    GuildSettings {
+        discord_guild_id: guild_id.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Loads the guild's settings, falling back to the defaults if they can't be read. If
+/// the channel rules can't be read, no channel is watched.
+pub fn for_guild(conn: &Arc<Mutex<Connection>>, guild_id: &str) -> Settings {
+    let stored = match get_guild_settings_by_guild_id(conn, guild_id) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("failed to load guild settings, using defaults: {e}");
+            None
+        }
+    };
+
+    Settings {
+        stored: stored.unwrap_or_else(|| defaults(guild_id)),
+        channel_rules: channel_rules(conn, guild_id),
+    }
+}
+
+fn channel_rules(conn: &Arc<Mutex<Connection>>, guild_id: &str) -> Option<Vec<ChannelRule>> {
+    match get_channel_rules_by_guild_id(conn, guild_id) {
+        Ok(r) => Some(r),
+        Err(e) => {
+            error!("failed to load channel rules, watching no channels: {e}");
+            None
+        }
+    }
+}
+
+/// Applies `change` to the guild's stored settings and saves them.
+pub fn update(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    change: impl FnOnce(&mut GuildSettings),
+) -> Result<Settings> {
+    let mut stored = match get_guild_settings_by_guild_id(conn, guild_id) {
+        Ok(s) => s.unwrap_or_else(|| defaults(guild_id)),
+        Err(e) => {
+            error!("failed to load guild settings: {e}");
+            return Err(e.to_string().into());
+        }
+    };
+
+    change(&mut stored);
+
+    if let Err(e) = upsert_guild_settings(conn, &stored) {
+        error!("failed to save guild settings: {e}");
+        return Err(e.to_string().into());
+    }
+
+    Ok(Settings {
+        stored,
+        channel_rules: channel_rules(conn, guild_id),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_rules(rules: &[(u64, bool)]) -> Settings {
+        Settings {
+            stored: defaults("1"),
+            channel_rules: Some(
+                rules
+                    .iter()
+                    .map(|(channel, allow)| ChannelRule {
+                        discord_guild_id: String::from("1"),
+                        channel_id: channel.to_string(),
+                        allow: *allow,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn watches_everything_without_rules() {
+        let settings = with_rules(&[]);
+
+        assert!(!settings.has_channel_rules());
+        assert!(settings.watches(&[ChannelId::new(10)]));
+    }
+
+    #[test]
+    fn watches_only_allowed_channels_once_any_are() {
+        let settings = with_rules(&[(10, true)]);
+
+        assert!(settings.watches(&[ChannelId::new(10)]));
+        assert!(!settings.watches(&[ChannelId::new(11)]));
+    }
+
+    #[test]
+    fn watches_channels_in_an_allowed_category() {
+        let settings = with_rules(&[(20, true)]);
+
+        assert!(settings.watches(&[ChannelId::new(10), ChannelId::new(20)]));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let settings = with_rules(&[(20, true), (10, false)]);
+
+        assert!(!settings.watches(&[ChannelId::new(10), ChannelId::new(20)]));
+        assert!(settings.watches(&[ChannelId::new(11), ChannelId::new(20)]));
+    }
+
+    #[test]
+    fn watches_nothing_if_rules_could_not_be_read() {
+        let settings = Settings {
+            stored: defaults("1"),
+            channel_rules: None,
+        };
+
+        assert!(settings.has_channel_rules());
+        assert!(!settings.watches(&[ChannelId::new(10)]));
+    }
+}
@@ -1,23 +1,28 @@
-use log::error;
-use ordermap::OrderSet;
 use prawn::client::TidalClient;
 use regex::Regex;
 use rspotify::clients::BaseClient;
-use rspotify::model::{AlbumId, FullAlbum, FullTrack, Image, PlayableId, TrackId};
+use rspotify::model::{
+    AlbumId, ArtistId, FullAlbum, FullTrack, PlayableId, SearchResult, SearchType, TrackId,
+};
+use rspotify::prelude::Id;
 use rspotify::{AuthCodeSpotify, ClientCredsSpotify, Config, Credentials, OAuth, Token, scopes};
-use std::env;
+use rusqlite::Connection;
 use std::error::Error;
-use std::fmt::{Display, Formatter};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use tracing::error;
 
-use crate::discord::ServiceResources;
-use crate::tidal;
+use crate::cards::Card;
+use crate::discord::{Lookup, PostedLink, ServiceResources};
+use crate::matching::MatchFailure;
+use crate::{config, metrics, ratelimit, tidal};
 
 const SPOTIFY_DOMAIN: &str = "open.spotify.com";
 const SPOTIFY_SHORTENED_DOMAIN: &str = "spotify.link";
 const MAX_REDIRECT_DEPTH: u32 = 5;
 
 const SPOTIFY_ALBUM_LINK: &str = "https://open.spotify.com/album/";
+// The most artists Spotify returns from a single request.
+const MAX_ARTISTS_PER_REQUEST: usize = 50;
 
 type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
@@ -73,17 +78,6 @@ pub fn extract_ids(link: &str) -> Vec<IdType> {
         .collect()
 }
 
-#[derive(Debug, Clone)]
-pub struct SpotifyErr;
-
-impl Display for SpotifyErr {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "error ")
-    }
-}
-
-impl Error for SpotifyErr {}
-
 fn expand_spotify_short_link(link: &str, depth: u32) -> Result<String> {
     if depth >= MAX_REDIRECT_DEPTH {
         return Ok(link.to_string());
@@ -107,20 +101,20 @@ fn expand_spotify_short_link(link: &str, depth: u32) -> Result<String> {
 }
 
 pub fn init_spotify_from_token(token: Token) -> Result<AuthCodeSpotify> {
+    let spotify_config = config::spotify()?;
     let config = Config::default();
     // Please notice that protocol of redirect_uri, make sure it's http (or
     // https). It will fail if you mix them up.
     let oauth = OAuth {
         scopes: scopes!("playlist-modify-public"),
-        redirect_uri: env::var("SPOTIFY_REDIRECT_URI")
-            .unwrap_or_else(|_| SPOTIFY_DOMAIN.to_string()),
+        redirect_uri: spotify_config
+            .token_redirect_uri
+            .clone()
+            .unwrap_or_else(|| SPOTIFY_DOMAIN.to_string()),
         ..Default::default()
     };
 
-    let Some(creds) = Credentials::from_env() else {
-        error!("Spotify credentials not set");
-        return Err(SpotifyErr.into());
-    };
+    let creds = credentials()?;
 
     Ok(AuthCodeSpotify::from_token_with_config(
         token, creds, oauth, config,
@@ -130,8 +124,13 @@ pub fn init_spotify_from_token(token: Token) -> Result<AuthCodeSpotify> {
 pub fn init_spotify() -> Result<AuthCodeSpotify> {
     let config = Config::default();
 
-    let base_redirect_uri = url::Url::parse(env::var("BASE_REDIRECT_URI")?.as_str())?;
-    let redirect_uri = String::from(base_redirect_uri.join("/callback")?.as_str());
+    let redirect_uri = String::from(
+        config::get()
+            .server
+            .base_redirect_uri
+            .join("/callback")?
+            .as_str(),
+    );
 
     let oauth = OAuth {
         scopes: scopes!("playlist-modify-public"),
@@ -139,14 +138,20 @@ pub fn init_spotify() -> Result<AuthCodeSpotify> {
         ..Default::default()
     };
 
-    let Some(creds) = Credentials::from_env() else {
-        error!("Spotify credentials not set");
-        return Err(SpotifyErr.into());
-    };
+    let creds = credentials()?;
 
     Ok(AuthCodeSpotify::with_config(creds, oauth, config))
 }
 
+pub fn credentials() -> Result<Credentials> {
+    let spotify_config = config::spotify()?;
+
+    Ok(Credentials::new(
+        spotify_config.client_id.as_str(),
+        spotify_config.client_secret.as_str(),
+    ))
+}
+
 pub fn extract_playlist_id(link: &str) -> Option<String> {
     let re = Regex::new(r"https://open\.spotify\.com/playlist/([a-zA-Z0-9]+)")
         .expect("unable to compile regex");
@@ -154,33 +159,6 @@ pub fn extract_playlist_id(link: &str) -> Option<String> {
     Some(re.captures(link)?.get(1)?.as_str().to_string())
 }
 
-pub async fn get_album_cover_image_from_track(
-    spotify: &ClientCredsSpotify,
-    track_id: &str,
-) -> Result<Option<Image>> {
-    let track_id = TrackId::from_id(track_id)?;
-
-    let track: FullTrack = spotify.track(track_id, None).await?;
-
-    Ok(track.album.images.into_iter().next())
-}
-
-pub async fn get_album_cover_image(
-    spotify: &ClientCredsSpotify,
-    album_id: Option<&str>,
-) -> Result<Option<Image>> {
-    match album_id {
-        None => Ok(None),
-        Some(id) => {
-            let album_id = AlbumId::from_id(id)?;
-
-            let album: FullAlbum = spotify.album(album_id, None).await?;
-
-            Ok(album.images.into_iter().next())
-        }
-    }
-}
-
 pub async fn get_album_track_ids(
     client: &Arc<ClientCredsSpotify>,
     album_id: String,
@@ -193,7 +171,11 @@ pub async fn get_album_track_ids(
         }
     };
 
-    let album = match client.album(album_id, None).await {
+    let album = match ratelimit::call("spotify", "album", None, || {
+        client.album(album_id.clone(), None)
+    })
+    .await
+    {
         Ok(a) => a,
         Err(e) => {
             error!("Failed to get album: {e}");
@@ -244,36 +226,20 @@ pub async fn get_track_ids<'a>(
     track_ids
 }
 
-pub async fn get_album_images(
-    client: &Arc<ClientCredsSpotify>,
-    spotify_ids: &Vec<IdType>,
-) -> OrderSet<String> {
-    let mut images = OrderSet::new();
-
-    for id in spotify_ids {
-        match id {
-            IdType::Track(t) => {
-                if let Ok(Some(image)) = get_album_cover_image_from_track(client, t.as_str()).await
-                {
-                    images.insert(image.url);
-                }
-            }
-            IdType::Album(a) => {
-                if let Ok(Some(image)) = get_album_cover_image(client, Some(a.as_str())).await {
-                    images.insert(image.url);
-                }
-            }
-        }
-    }
-
-    images
-}
-
 pub enum SpotifyResource {
     Album(Box<FullAlbum>),
     Track(Box<FullTrack>),
 }
 
+impl SpotifyResource {
+    pub fn id(&self) -> Option<IdType> {
+        match self {
+            Self::Album(album) => Some(IdType::Album(album.id.id().to_string())),
+            Self::Track(track) => track.id.as_ref().map(|t| IdType::Track(t.id().to_string())),
+        }
+    }
+}
+
 pub async fn get_spotify_resources(
     client: &ClientCredsSpotify,
     spotify_ids: Vec<IdType>,
@@ -282,12 +248,20 @@ pub async fn get_spotify_resources(
     for id in spotify_ids {
         let resource = match id {
             IdType::Album(i) => {
-                let album = client.album(AlbumId::from_id(i)?, None).await?;
+                let album_id = AlbumId::from_id(i)?;
+                let album = ratelimit::call("spotify", "album", None, || {
+                    client.album(album_id.clone(), None)
+                })
+                .await?;
 
                 SpotifyResource::Album(Box::new(album))
             }
             IdType::Track(i) => {
-                let track = client.track(TrackId::from_id(i)?, None).await?;
+                let track_id = TrackId::from_id(i)?;
+                let track = ratelimit::call("spotify", "track", None, || {
+                    client.track(track_id.clone(), None)
+                })
+                .await?;
 
                 SpotifyResource::Track(Box::new(track))
             }
@@ -299,40 +273,149 @@ pub async fn get_spotify_resources(
     Ok(resources)
 }
 
+pub async fn search_tracks(
+    client: &ClientCredsSpotify,
+    query: &str,
+    limit: u32,
+) -> Result<Vec<FullTrack>> {
+    let result = ratelimit::call("spotify", "search_tracks", None, || {
+        client.search(query, SearchType::Track, None, None, Some(limit), None)
+    })
+    .await?;
+
+    let SearchResult::Tracks(tracks) = result else {
+        return Ok(vec![]);
+    };
+
+    Ok(tracks.items)
+}
+
+/// The genres of each resource's primary artist, lined up with `resources`. Missing
+/// artists, and lookups that fail, give no genres.
+async fn primary_artist_genres(
+    spotify_client: &ClientCredsSpotify,
+    resources: &[SpotifyResource],
+) -> Vec<Vec<String>> {
+    let primary: Vec<Option<ArtistId<'static>>> = resources
+        .iter()
+        .map(|r| {
+            match r {
+                SpotifyResource::Album(album) => album.artists.first(),
+                SpotifyResource::Track(track) => track.artists.first(),
+            }
+            .and_then(|a| a.id.clone())
+        })
+        .collect();
+
+    let mut ids: Vec<ArtistId<'static>> = vec![];
+
+    for id in primary.iter().flatten() {
+        if !ids.contains(id) {
+            ids.push(id.clone());
+        }
+    }
+
+    let mut artists = vec![];
+
+    for chunk in ids.chunks(MAX_ARTISTS_PER_REQUEST) {
+        match ratelimit::call("spotify", "artists", None, || {
+            spotify_client.artists(chunk.to_vec())
+        })
+        .await
+        {
+            Ok(a) => artists.extend(a),
+            Err(e) => error!("failed to get artists: {e}"),
+        }
+    }
+
+    primary
+        .iter()
+        .map(|id| {
+            id.as_ref()
+                .and_then(|id| artists.iter().find(|a| a.id == *id))
+                .map(|a| a.genres.clone())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
 pub async fn extract_resources(
+    conn: &Arc<Mutex<Connection>>,
     spotify_client: &ClientCredsSpotify,
-    tidal_client: &TidalClient,
+    tidal_client: Option<&TidalClient>,
     content: &str,
+    lookup: Lookup,
 ) -> Vec<ServiceResources> {
     if !contains_spotify_link(content) {
         return vec![];
     }
 
-    let spotify_ids = extract_ids(content);
+    let mut spotify_ids = extract_ids(content);
+
+    metrics::record_links_extracted("spotify", spotify_ids.len());
+
+    let posted = ServiceResources::Posted(spotify_ids.iter().map(PostedLink::spotify).collect());
+
+    if !lookup.albums {
+        spotify_ids.retain(|i| matches!(i, IdType::Track(_)));
+    }
+
+    if spotify_ids.is_empty() {
+        return vec![posted];
+    }
 
     let spotify_resources = match get_spotify_resources(spotify_client, spotify_ids.clone()).await {
         Ok(s) => s,
         Err(e) => {
             error!("failed to get spotify_resources: {e}");
-            return vec![];
+            return vec![posted, ServiceResources::Spotify(spotify_ids)];
         }
     };
 
-    match tidal::get_tidal_ids_from_spotify_resources(
-        tidal_client,
-        spotify_client,
-        &spotify_resources,
-    )
-    .await
-    {
-        Ok(t) => [
-            ServiceResources::Spotify(spotify_ids),
-            ServiceResources::Tidal(t),
-        ]
-        .to_vec(),
-        Err(e) => {
-            error!("failed to get tidal ids: {e}");
-            [ServiceResources::Spotify(spotify_ids)].to_vec()
+    let tidal_matches = match tidal_client {
+        Some(t) => {
+            tidal::get_tidal_ids_from_spotify_resources(conn, t, spotify_client, &spotify_resources)
+                .await
+        }
+        None => vec![],
+    };
+
+    let genres = primary_artist_genres(spotify_client, &spotify_resources).await;
+
+    let cards = spotify_resources
+        .iter()
+        .zip(genres)
+        .enumerate()
+        .map(|(i, (r, g))| {
+            Card::from_spotify(r, tidal_matches.get(i).and_then(|m| m.as_ref().ok()), g)
+        })
+        .collect();
+
+    let mut resources = vec![
+        posted,
+        ServiceResources::Spotify(spotify_ids),
+        ServiceResources::Cards(cards),
+    ];
+
+    if tidal_client.is_none() {
+        return resources;
+    }
+
+    let mut tidal_ids = vec![];
+    let mut failures: Vec<MatchFailure> = vec![];
+
+    for matched in tidal_matches {
+        match matched {
+            Ok(id) => tidal_ids.push(id),
+            Err(failure) => failures.push(failure),
         }
     }
+
+    resources.push(ServiceResources::Tidal(tidal_ids));
+
+    if !failures.is_empty() {
+        resources.push(ServiceResources::Unmatched(failures));
+    }
+
+    resources
 }
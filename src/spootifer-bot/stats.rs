@@ -0,0 +1,220 @@
+use crate::db::{
+    ArchiveCount, RankBy, count_message_links_by, count_message_links_by_month,
+    get_message_link_genres,
+};
+use crate::pages;
+use chrono::{Datelike, Days, NaiveDate, Utc};
+use rusqlite::Connection;
+use serenity::all::CreateEmbed;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+// How many entries each top list shows, and how many months of history.
+const TOP: usize = 5;
+const MONTHS: usize = 6;
+const LEADERBOARD_SIZE: usize = 10;
+
+// Matches every created_at, for stats over all time.
+const ALL_TIME: &str = "";
+
+/// What has been posted in a guild over all time, for `/stats`.
+pub struct GuildStats {
+    services: Vec<ArchiveCount>,
+    months: Vec<ArchiveCount>,
+    posters: Vec<ArchiveCount>,
+    artists: Vec<ArchiveCount>,
+    genres: Vec<(String, usize)>,
+}
+
+impl GuildStats {
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    pub fn embed(&self) -> CreateEmbed {
+        let links: i64 = self.services.iter().map(|s| s.links).sum();
+        let tracks: i64 = self.services.iter().map(|s| s.tracks).sum();
+
+        let services = self
+            .services
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}: {} links, {} tracks",
+                    pages::service_display_name(s.key.as_str()),
+                    s.links,
+                    s.tracks
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let months = self
+            .months
+            .iter()
+            .map(|m| format!("{}: {} tracks", m.key, m.tracks))
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let genres = self
+            .genres
+            .iter()
+            .map(|(g, n)| format!("{g} ({n})"))
+            .collect::<Vec<String>>();
+
+        CreateEmbed::new()
+            .title("Archive stats")
+            .description(format!(
+                "{links} links posted, with {tracks} tracks in them."
+            ))
+            .field("By service", services, false)
+            .field("By month", or_none(months), true)
+            .field("Top posters", or_none(poster_lines(&self.posters)), true)
+            .field("Top artists", or_none(count_lines(&self.artists)), false)
+            .field("Top genres", or_none(genres.join(", ")), false)
+    }
+}
+
+/// Loads the guild's archive stats, leaving out anyone who opted out of archiving.
+pub fn for_guild(conn: &Arc<Mutex<Connection>>, guild_id: &str) -> Result<GuildStats> {
+    let services = counts(conn, guild_id, ALL_TIME, "service", RankBy::Links, TOP)?;
+
+    let months = match count_message_links_by_month(conn, guild_id, MONTHS) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("failed to count links by month: {e}");
+            return Err(e.to_string().into());
+        }
+    };
+
+    Ok(GuildStats {
+        services,
+        months,
+        // Posters are listed with their track counts, so they're ranked by them too.
+        posters: counts(
+            conn,
+            guild_id,
+            ALL_TIME,
+            "author_discord_user_id",
+            RankBy::Tracks,
+            TOP,
+        )?,
+        artists: counts(conn, guild_id, ALL_TIME, "artist", RankBy::Links, TOP)?,
+        genres: top_genres(conn, guild_id, ALL_TIME, TOP)?,
+    })
+}
+
+/// The guild's top posters since `since`, for `/leaderboard`.
+pub fn leaderboard(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    since: &str,
+) -> Result<Vec<ArchiveCount>> {
+    counts(
+        conn,
+        guild_id,
+        since,
+        "author_discord_user_id",
+        RankBy::Tracks,
+        LEADERBOARD_SIZE,
+    )
+}
+
+pub fn leaderboard_embed(title: &str, posters: &[ArchiveCount]) -> CreateEmbed {
+    CreateEmbed::new()
+        .title(title)
+        .description(or_none(poster_lines(posters)))
+}
+
+/// The start of the current week (Monday) in the format `created_at` is stored in.
+pub fn week_start() -> String {
+    let today = Utc::now().date_naive();
+
+    start_of(today - Days::new(u64::from(today.weekday().num_days_from_monday())))
+}
+
+/// The start of the current month in the format `created_at` is stored in.
+pub fn month_start() -> String {
+    let today = Utc::now().date_naive();
+
+    start_of(today.with_day(1).unwrap_or(today))
+}
+
+fn start_of(day: NaiveDate) -> String {
+    format!("{day} 00:00:00")
+}
+
+pub fn counts(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    since: &str,
+    column: &str,
+    rank: RankBy,
+    limit: usize,
+) -> Result<Vec<ArchiveCount>> {
+    match count_message_links_by(conn, guild_id, since, column, rank, limit) {
+        Ok(c) => Ok(c),
+        Err(e) => {
+            error!("failed to count links by {column}: {e}");
+            Err(e.to_string().into())
+        }
+    }
+}
+
+fn top_genres(
+    conn: &Arc<Mutex<Connection>>,
+    guild_id: &str,
+    since: &str,
+    limit: usize,
+) -> Result<Vec<(String, usize)>> {
+    let genres = match get_message_link_genres(conn, guild_id, since) {
+        Ok(g) => g,
+        Err(e) => {
+            error!("failed to get genres: {e}");
+            return Err(e.to_string().into());
+        }
+    };
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+
+    for genre in genres.into_iter().flatten() {
+        *counts.entry(genre).or_default() += 1;
+    }
+
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(limit);
+
+    Ok(counts)
+}
+
+fn poster_lines(posters: &[ArchiveCount]) -> String {
+    posters
+        .iter()
+        .enumerate()
+        .map(|(i, p)| format!("{}. <@{}>: {} tracks", i + 1, p.key, p.tracks))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn count_lines(counts: &[ArchiveCount]) -> String {
+    counts
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{}. {} ({})", i + 1, c.key, c.links))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Embed fields can't be empty.
+pub fn or_none(value: String) -> String {
+    if value.is_empty() {
+        String::from("Nothing yet")
+    } else {
+        value
+    }
+}
@@ -0,0 +1,88 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+// Kept below fly.toml's kill_timeout so writes can finish before the machine is killed.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Resolves once the process receives SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("unable to listen for SIGTERM: {e}");
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!("unable to listen for SIGINT: {e}");
+            }
+            return;
+        }
+    };
+
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => match r {
+            Ok(()) => info!("received SIGINT"),
+            Err(e) => error!("unable to listen for SIGINT: {e}"),
+        },
+        _ = sigterm.recv() => info!("received SIGTERM"),
+    }
+}
+
+/// Runs a background worker until shutdown is signalled, restarting it whenever it
+/// exits or panics.
+pub fn spawn_worker<F, Fut>(
+    name: &'static str,
+    mut shutdown: watch::Receiver<bool>,
+    make_worker: F,
+) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            let mut worker = tokio::spawn(make_worker());
+
+            tokio::select! {
+                r = &mut worker => match r {
+                    Ok(()) => warn!("{name} exited, restarting"),
+                    Err(e) => error!("{name} failed, restarting: {e}"),
+                },
+                _ = shutdown.wait_for(|s| *s) => {
+                    worker.abort();
+                    info!("stopped {name}");
+                    return;
+                }
+            }
+
+            tokio::select! {
+                () = tokio::time::sleep(RESTART_DELAY) => {}
+                _ = shutdown.wait_for(|s| *s) => {
+                    info!("stopped {name}");
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Stops accepting new tracked work and waits for what is already running to finish.
+pub async fn drain(in_flight: &TaskTracker) {
+    in_flight.close();
+
+    info!("waiting on {} in-flight tasks", in_flight.len());
+
+    if tokio::time::timeout(DRAIN_TIMEOUT, in_flight.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "gave up waiting on {} in-flight tasks after {DRAIN_TIMEOUT:?}",
+            in_flight.len()
+        );
+    }
+}
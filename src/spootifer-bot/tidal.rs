@@ -1,7 +1,9 @@
-use crate::discord::ServiceResources;
-use crate::error;
+use crate::cards::Card;
+use crate::discord::{Lookup, PostedLink, ServiceResources};
+use crate::matching::{self, MatchFailure, MatchFailureReason};
 use crate::spotify::{IdType, SpotifyResource};
-use log::{info, warn};
+use crate::{config, metrics, ratelimit};
+use chrono::{DateTime, Utc};
 use prawn::apis::Api;
 use prawn::client::{
     OAuthConfig, RetryConfig, TidalClient, TidalClientConfig, TidalClientError, Token,
@@ -14,10 +16,12 @@ use regex::Regex;
 use rspotify::ClientCredsSpotify;
 use rspotify::model::{FullAlbum, FullTrack};
 use rspotify::prelude::BaseClient;
-use std::env;
+use rusqlite::Connection;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tracing::{error, info, instrument, warn};
 
 static TIDAL_DOMAIN: &str = "tidal.com";
 static TIDAL_ALBUM_LINK: &str = "https://tidal.com/album";
@@ -54,7 +58,7 @@ impl From<TidalClientError> for TidalError {
 }
 
 pub fn init_tidal() -> Result<TidalClient> {
-    let client_id = env::var("TIDAL_CLIENT_ID")?;
+    let client_id = config::tidal()?.client_id.clone();
 
     let redirect_uri = get_redirect_uri()?;
 
@@ -71,9 +75,12 @@ pub fn init_tidal() -> Result<TidalClient> {
     Ok(prawn::client::TidalClient::new(config)?)
 }
 
-pub async fn init_tidal_with_secret() -> Result<TidalClient> {
-    let client_id = env::var("TIDAL_CLIENT_ID")?;
-    let client_secret = env::var("TIDAL_CLIENT_SECRET")?;
+/// Initializes the app-level client-credential client, also returning when its token
+/// expires if that could be determined.
+pub async fn init_tidal_with_secret() -> Result<(TidalClient, Option<DateTime<Utc>>)> {
+    let tidal_config = config::tidal()?;
+    let client_id = tidal_config.client_id.clone();
+    let client_secret = tidal_config.client_secret.clone();
 
     let redirect_uri = get_redirect_uri()?;
 
@@ -93,11 +100,23 @@ pub async fn init_tidal_with_secret() -> Result<TidalClient> {
         .exchange_client_credentials_for_token(DEFAULT_SCOPES.to_vec())
         .await?;
 
-    Ok(client.with_token(token)?)
+    let expires_at = parse_token_expiry(token.expiry.as_str());
+
+    Ok((client.with_token(token)?, expires_at))
+}
+
+pub fn parse_token_expiry(expiry: &str) -> Option<DateTime<Utc>> {
+    match DateTime::parse_from_rfc3339(expiry) {
+        Ok(t) => Some(t.to_utc()),
+        Err(e) => {
+            warn!("unable to parse tidal token expiry {expiry}: {e}");
+            None
+        }
+    }
 }
 
 pub fn init_tidal_with_token(token: Token) -> Result<TidalClient> {
-    let client_id = env::var("TIDAL_CLIENT_ID")?;
+    let client_id = config::tidal()?.client_id.clone();
 
     let redirect_uri = get_redirect_uri()?;
 
@@ -125,9 +144,7 @@ pub static DEFAULT_SCOPES: &[&str] = &[
 ];
 
 pub fn get_redirect_uri() -> Result<String> {
-    let base_uri = env::var("BASE_REDIRECT_URI")?;
-
-    Ok(format!("{base_uri}/callback"))
+    Ok(config::get().server.callback_uri())
 }
 
 pub fn contains_tidal_link(msg: &str) -> bool {
@@ -181,10 +198,12 @@ pub fn is_album(link: &str) -> bool {
 pub async fn get_album_track_ids(client: &TidalClient, album_id: String) -> Result<Vec<String>> {
     info!("getting tracks for album {album_id}");
     let mut track_ids: Vec<String> = vec![];
-    let album_tracks = client
-        .albums_api()
-        .get_album_items(album_id.as_str(), None, None, None, None)
-        .await?;
+    let album_tracks = ratelimit::call("tidal", "get_album_items", None, || {
+        client
+            .albums_api()
+            .get_album_items(album_id.as_str(), None, None, None, None)
+    })
+    .await?;
 
     let Some(album_tracks_data) = album_tracks.data else {
         return Err(TidalError::ApiError {
@@ -204,10 +223,16 @@ pub async fn get_album_track_ids(client: &TidalClient, album_id: String) -> Resu
 
     let mut maybe_next = album_tracks.links.meta;
     while let Some(next) = maybe_next.clone() {
-        let album_tracks = client
-            .albums_api()
-            .get_album_items(album_id.as_str(), Some(&next.next_cursor), None, None, None)
-            .await?;
+        let album_tracks = ratelimit::call("tidal", "get_album_items", None, || {
+            client.albums_api().get_album_items(
+                album_id.as_str(),
+                Some(&next.next_cursor),
+                None,
+                None,
+                None,
+            )
+        })
+        .await?;
         let Some(album_tracks_data) = album_tracks.data else {
             return Err(TidalError::ApiError {
                 api: String::from("album_track_ids"),
@@ -253,34 +278,77 @@ pub enum TidalResource {
     Track(String),
 }
 
+/// Matches each Spotify resource on Tidal, using a `/fix_match` override when there is
+/// one. The results line up with `spotify_resources`.
 pub async fn get_tidal_ids_from_spotify_resources(
+    conn: &Arc<Mutex<Connection>>,
     tidal_client: &TidalClient,
     spotify_client: &ClientCredsSpotify,
-    spotify_resources: &Vec<SpotifyResource>,
-) -> Result<Vec<TidalResource>> {
-    let mut ids = vec![];
+    spotify_resources: &[SpotifyResource],
+) -> Vec<std::result::Result<TidalResource, MatchFailure>> {
+    let mut matches = vec![];
 
     for resource in spotify_resources {
-        let Some(resource) = (match resource {
+        let description = match resource {
+            SpotifyResource::Album(album) => matching::describe(
+                album.name.as_str(),
+                album.artists.first().map(|a| a.name.as_str()),
+            ),
+            SpotifyResource::Track(track) => matching::describe(
+                track.name.as_str(),
+                track.artists.first().map(|a| a.name.as_str()),
+            ),
+        };
+
+        let overridden = match resource.id().map(|id| matching::tidal_override(conn, &id)) {
+            Some(Ok(o)) => o,
+            Some(Err(_)) => {
+                matches.push(Err(MatchFailure {
+                    from: "spotify",
+                    to: "tidal",
+                    description,
+                    reason: MatchFailureReason::LookupFailed,
+                }));
+                continue;
+            }
+            None => None,
+        };
+
+        if let Some(t) = overridden {
+            matches.push(Ok(t));
+            continue;
+        }
+
+        let matched = match resource {
             SpotifyResource::Album(album) => match_album(tidal_client, album)
                 .await
                 .map(TidalResource::Album),
-
             SpotifyResource::Track(track) => match_track(tidal_client, spotify_client, track)
                 .await
                 .map(TidalResource::Track),
-        }) else {
-            warn!("failed to match a tidal resource");
-            continue;
         };
 
-        ids.push(resource);
+        metrics::record_match("spotify", "tidal", matched.is_ok());
+
+        matches.push(matched.map_err(|reason| {
+            warn!("failed to match {description} on tidal: {reason}");
+            MatchFailure {
+                from: "spotify",
+                to: "tidal",
+                description,
+                reason,
+            }
+        }));
     }
 
-    Ok(ids)
+    matches
 }
 
-async fn match_album(tidal_client: &TidalClient, album: &FullAlbum) -> Option<String> {
+#[instrument(skip_all, fields(from = "spotify", to = "tidal", album = %album.name))]
+async fn match_album(
+    tidal_client: &TidalClient,
+    album: &FullAlbum,
+) -> std::result::Result<String, MatchFailureReason> {
     let artist = album
         .artists
         .first()
@@ -288,43 +356,44 @@ async fn match_album(tidal_client: &TidalClient, album: &FullAlbum) -> Option<St
 
     let search_string = album.name.clone() + " " + artist;
 
-    let maybe_matched_album = match_album_with_search(tidal_client, album, search_string).await;
-
-    if maybe_matched_album.is_some() {
-        return maybe_matched_album;
-    }
+    let with_artist = match match_album_with_search(tidal_client, album, search_string).await {
+        Ok(id) => return Ok(id),
+        Err(reason) => reason,
+    };
 
     let search_string_no_artist = album.name.clone();
 
-    match_album_with_search(tidal_client, album, search_string_no_artist).await
+    match_album_with_search(tidal_client, album, search_string_no_artist)
+        .await
+        .map_err(|reason| reason.max(with_artist))
 }
 
 async fn match_album_with_search(
     tidal_client: &TidalClient,
     album: &FullAlbum,
     search_string: String,
-) -> Option<String> {
-    let search = match tidal_client
-        .search_results_api()
-        .get_search_result_albums(
+) -> std::result::Result<String, MatchFailureReason> {
+    let search = match ratelimit::call("tidal", "search_albums", None, || {
+        tidal_client.search_results_api().get_search_result_albums(
             search_string.as_str(),
             Some("INCLUDE"),
             None,
             None,
             Some(vec![String::from("albums")]),
         )
-        .await
+    })
+    .await
     {
         Ok(s) => s,
         Err(e) => {
             error!("failed to do album search: {e}");
-            return None;
+            return Err(MatchFailureReason::LookupFailed);
         }
     };
 
-    let Some(top_albums) = search.included else {
-        error!("relationships missing");
-        return None;
+    let Some(top_albums) = search.included.filter(|a| !a.is_empty()) else {
+        warn!("no album results for search {search_string}");
+        return Err(MatchFailureReason::NoSearchResults);
     };
 
     info!("{} album results", top_albums.len());
@@ -340,10 +409,10 @@ async fn match_album_with_search(
         album_matches(attrs.as_ref(), album)
     }) else {
         error!("no album matched search {search_string}");
-        return None;
+        return Err(MatchFailureReason::BelowThreshold);
     };
 
-    Some(top_album.id.clone())
+    Ok(top_album.id.clone())
 }
 
 fn album_matches(attrs: &AlbumsAttributes, full_spotify_album: &FullAlbum) -> bool {
@@ -390,6 +459,28 @@ fn track_matches(tidal_track: &TracksAttributes, spotify_track: &FullTrack) -> b
         }) && normalize_tidal_track_name == normalized_spotify_track_name)
 }
 
+// Explains why none of `candidates` matched: if one had the same title the ISRC (and
+// duration) must have differed, otherwise nothing was close.
+fn track_mismatch_reason<'a>(
+    mut candidates: impl Iterator<Item = &'a TracksAttributes>,
+    spotify_track: &FullTrack,
+) -> MatchFailureReason {
+    let spotify_name = normalize_track_name(spotify_track.name.as_str());
+
+    if candidates.any(|t| normalize_track_name(t.title.as_str()) == spotify_name) {
+        MatchFailureReason::IsrcMismatch
+    } else {
+        MatchFailureReason::BelowThreshold
+    }
+}
+
+fn tracks_in_list(list: &[IncludedInner]) -> impl Iterator<Item = &TracksAttributes> {
+    list.iter().filter_map(|i| match i {
+        IncludedInner::Tracks(t) => t.attributes.as_deref(),
+        _ => None,
+    })
+}
+
 fn track_matches_in_list(maybe_track: &IncludedInner, spotify_track: &FullTrack) -> bool {
     let IncludedInner::Tracks(track) = maybe_track else {
         return false;
@@ -406,47 +497,56 @@ async fn find_track_in_album(
     client: &TidalClient,
     spotify_client: &ClientCredsSpotify,
     track: &FullTrack,
-) -> Option<String> {
+) -> std::result::Result<String, MatchFailureReason> {
     let album_name = track.album.name.clone();
-    let artist_name = track.artists.first()?.name.clone();
+    let Some(artist) = track.artists.first() else {
+        return Err(MatchFailureReason::LookupFailed);
+    };
+    let artist_name = artist.name.clone();
 
     let search_string = album_name + " " + artist_name.as_str();
 
-    let search = match client
-        .search_results_api()
-        .get_search_result_albums(
+    let search = match ratelimit::call("tidal", "search_albums", None, || {
+        client.search_results_api().get_search_result_albums(
             search_string.as_str(),
             Some("INCLUDE"),
             None,
             None,
             Some(vec![String::from("albums")]),
         )
-        .await
+    })
+    .await
     {
         Ok(s) => s,
         Err(e) => {
             error!("failed to do search: {e}");
-            return None;
+            return Err(MatchFailureReason::LookupFailed);
         }
     };
 
     let album_id = track.album.id.clone();
 
     let full_album = if let Some(id) = album_id {
-        let album_resp = spotify_client.album(id, None).await;
+        let album_resp = ratelimit::call("spotify", "album", None, || {
+            spotify_client.album(id.clone(), None)
+        })
+        .await;
         match album_resp {
             Ok(a) => a,
             Err(e) => {
                 error!("failed to get spotify album: {e}");
-                return None;
+                return Err(MatchFailureReason::LookupFailed);
             }
         }
     } else {
         error!("album id not present");
-        return None;
+        return Err(MatchFailureReason::LookupFailed);
+    };
+
+    let Some(albums) = search.included.filter(|a| !a.is_empty()) else {
+        return Err(MatchFailureReason::NoSearchResults);
     };
 
-    let albums = search.included?;
     let Some(IncludedInner::Albums(top_album)) = albums.iter().find(|a| -> bool {
         let IncludedInner::Albums(album) = a else {
             return false;
@@ -458,23 +558,27 @@ async fn find_track_in_album(
         album_matches(attrs.as_ref(), &full_album)
     }) else {
         warn!("failed to match album");
-        return None;
+        return Err(MatchFailureReason::BelowThreshold);
     };
 
     let top_album_name = top_album.attributes.as_ref().unwrap().title.clone();
 
     info!("matched album id {} name {}", top_album.id, top_album_name);
 
-    let Ok(album_tracks_resp) = client
-        .albums_api()
-        .get_album(&top_album.id, None, Some(vec!["items".to_string()]), None)
-        .await
+    let Ok(album_tracks_resp) = ratelimit::call("tidal", "get_album", None, || {
+        client
+            .albums_api()
+            .get_album(&top_album.id, None, Some(vec!["items".to_string()]), None)
+    })
+    .await
     else {
         error!("failed to get album items");
-        return None;
+        return Err(MatchFailureReason::LookupFailed);
     };
 
-    let album_tracks = album_tracks_resp.included?;
+    let Some(album_tracks) = album_tracks_resp.included else {
+        return Err(MatchFailureReason::NoSearchResults);
+    };
 
     info!(
         "trying to match album tracks for returned album {}",
@@ -486,37 +590,45 @@ async fn find_track_in_album(
         .find(|t| -> bool { track_matches_in_list(t, track) })
     else {
         warn!("failed to match a track");
-        return None;
+        return Err(track_mismatch_reason(tracks_in_list(&album_tracks), track));
     };
 
-    Some(matched_track.id.clone())
+    Ok(matched_track.id.clone())
 }
 
-async fn find_track(client: &TidalClient, track: &FullTrack) -> Option<String> {
+async fn find_track(
+    client: &TidalClient,
+    track: &FullTrack,
+) -> std::result::Result<String, MatchFailureReason> {
     let track_name = track.name.clone();
-    let artist_name = track.artists.first()?.name.clone();
+    let Some(artist) = track.artists.first() else {
+        return Err(MatchFailureReason::LookupFailed);
+    };
+    let artist_name = artist.name.clone();
 
     let search_string = track_name + " " + artist_name.as_str();
 
-    let search = match client
-        .search_results_api()
-        .get_search_result_tracks(
+    let search = match ratelimit::call("tidal", "search_tracks", None, || {
+        client.search_results_api().get_search_result_tracks(
             search_string.as_str(),
             Some("INCLUDE"),
             None,
             None,
             Some(vec![String::from("tracks")]),
         )
-        .await
+    })
+    .await
     {
         Ok(s) => s,
         Err(e) => {
             error!("failed to do search: {e}");
-            return None;
+            return Err(MatchFailureReason::LookupFailed);
         }
     };
 
-    let search_included = search.included?;
+    let Some(search_included) = search.included.filter(|i| !i.is_empty()) else {
+        return Err(MatchFailureReason::NoSearchResults);
+    };
     let track_ref = &track;
 
     let Some(IncludedInner::Tracks(found_track)) = search_included
@@ -524,24 +636,60 @@ async fn find_track(client: &TidalClient, track: &FullTrack) -> Option<String> {
         .find(|t| -> bool { track_matches_in_list(t, track_ref) })
     else {
         warn!("failed to match a track");
-        return None;
+        return Err(track_mismatch_reason(
+            tracks_in_list(&search_included),
+            track,
+        ));
     };
 
-    Some(found_track.id.clone())
+    Ok(found_track.id.clone())
 }
 
+/// Searches Tidal for tracks, returning them with their artists and album, best match
+/// first.
+pub async fn search_tracks(
+    client: &TidalClient,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<FullTidalResource>> {
+    let search = ratelimit::call("tidal", "search_tracks", None, || {
+        client.search_results_api().get_search_result_tracks(
+            query,
+            Some("INCLUDE"),
+            None,
+            None,
+            Some(vec![String::from("tracks")]),
+        )
+    })
+    .await?;
+
+    // The relationship's data is in relevance order, which `included` isn't guaranteed to
+    // be. Includes can't be nested, so the artists take a lookup per track.
+    let tracks = search
+        .data
+        .unwrap_or_default()
+        .into_iter()
+        .take(limit)
+        .map(|t| TidalResource::Track(t.id))
+        .collect();
+
+    Ok(get_full_tidal_resources(client, tracks).await)
+}
+
+#[instrument(skip_all, fields(from = "spotify", to = "tidal", track = %track.name))]
 async fn match_track(
     client: &TidalClient,
     spotify_client: &ClientCredsSpotify,
     track: &FullTrack,
-) -> Option<String> {
-    let track_in_album = find_track_in_album(client, spotify_client, track).await;
-
-    if track_in_album.is_some() {
-        return track_in_album;
-    }
+) -> std::result::Result<String, MatchFailureReason> {
+    let in_album = match find_track_in_album(client, spotify_client, track).await {
+        Ok(id) => return Ok(id),
+        Err(reason) => reason,
+    };
 
-    find_track(client, track).await
+    find_track(client, track)
+        .await
+        .map_err(|reason| reason.max(in_album))
 }
 
 pub enum FullTidalResource {
@@ -549,6 +697,15 @@ pub enum FullTidalResource {
     Album(AlbumsSingleResourceDataDocument),
 }
 
+impl FullTidalResource {
+    pub fn resource(&self) -> TidalResource {
+        match self {
+            Self::Album(album) => TidalResource::Album(album.data.id.clone()),
+            Self::Track(track) => TidalResource::Track(track.data.id.clone()),
+        }
+    }
+}
+
 pub async fn get_full_tidal_resources(
     client: &TidalClient,
     resources: Vec<TidalResource>,
@@ -557,15 +714,15 @@ pub async fn get_full_tidal_resources(
     for resource in resources {
         match resource {
             TidalResource::Album(album_id) => {
-                match client
-                    .albums_api()
-                    .get_album(
+                match ratelimit::call("tidal", "get_album", None, || {
+                    client.albums_api().get_album(
                         album_id.as_str(),
                         None,
-                        Some(vec!["artists".to_string()]),
+                        Some(vec!["artists".to_string(), "coverArt".to_string()]),
                         None,
                     )
-                    .await
+                })
+                .await
                 {
                     Ok(a) => full_resources.push(FullTidalResource::Album(a)),
                     Err(e) => {
@@ -574,15 +731,15 @@ pub async fn get_full_tidal_resources(
                 }
             }
             TidalResource::Track(track_id) => {
-                match client
-                    .tracks_api()
-                    .get_track(
+                match ratelimit::call("tidal", "get_track", None, || {
+                    client.tracks_api().get_track(
                         track_id.as_str(),
                         None,
-                        Some(vec!["artists".to_string()]),
+                        Some(vec!["artists".to_string(), "albums".to_string()]),
                         None,
                     )
-                    .await
+                })
+                .await
                 {
                     Ok(t) => full_resources.push(FullTidalResource::Track(t)),
                     Err(e) => {
@@ -595,51 +752,140 @@ pub async fn get_full_tidal_resources(
     full_resources
 }
 
+pub fn artist_names(included: Option<&Vec<IncludedInner>>) -> Vec<String> {
+    included
+        .into_iter()
+        .flatten()
+        .filter_map(|i| match i {
+            IncludedInner::Artists(artist) => Some(artist.attributes.as_ref()?.name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn first_artist_name(included: Option<&Vec<IncludedInner>>) -> Option<String> {
+    artist_names(included).into_iter().next()
+}
+
+fn cover_art_in(included: Option<&Vec<IncludedInner>>) -> Option<String> {
+    included.into_iter().flatten().find_map(|i| match i {
+        IncludedInner::Artworks(artwork) => {
+            Some(artwork.attributes.as_ref()?.files.first()?.href.clone())
+        }
+        _ => None,
+    })
+}
+
+/// The cover art of a resource, or of the album a track is on, which takes another
+/// lookup since includes can't be nested.
+pub async fn get_cover_art_url(
+    client: &TidalClient,
+    resource: &FullTidalResource,
+) -> Option<String> {
+    let album_id = match resource {
+        FullTidalResource::Album(album) => return cover_art_in(album.included.as_ref()),
+        FullTidalResource::Track(track) => {
+            track.included.iter().flatten().find_map(|i| match i {
+                IncludedInner::Albums(album) => Some(album.id.clone()),
+                _ => None,
+            })?
+        }
+    };
+
+    match ratelimit::call("tidal", "get_album", None, || {
+        client.albums_api().get_album(
+            album_id.as_str(),
+            None,
+            Some(vec!["coverArt".to_string()]),
+            None,
+        )
+    })
+    .await
+    {
+        Ok(album) => cover_art_in(album.included.as_ref()),
+        Err(e) => {
+            error!("error fetching tidal album cover art: {e}");
+            None
+        }
+    }
+}
+
+fn describe(resource: &FullTidalResource) -> String {
+    let (title, artist) = match resource {
+        FullTidalResource::Album(album) => (
+            album.data.attributes.as_ref().map(|a| a.title.clone()),
+            first_artist_name(album.included.as_ref()),
+        ),
+        FullTidalResource::Track(track) => (
+            track.data.attributes.as_ref().map(|a| a.title.clone()),
+            first_artist_name(track.included.as_ref()),
+        ),
+    };
+
+    matching::describe(
+        title.as_deref().unwrap_or("Unknown title"),
+        artist.as_deref(),
+    )
+}
+
+#[instrument(skip_all, fields(from = "tidal", to = "spotify", album_id = %tidal_album.data.id))]
 async fn match_spotify_album(
     spotify_client: &ClientCredsSpotify,
-    tidal_album: AlbumsSingleResourceDataDocument,
-) -> Option<IdType> {
-    let album_attrs = tidal_album.data.attributes?;
+    tidal_album: &AlbumsSingleResourceDataDocument,
+) -> std::result::Result<IdType, MatchFailureReason> {
+    let Some(artist_name) = first_artist_name(tidal_album.included.as_ref()) else {
+        info!("no artist info");
+        return Err(MatchFailureReason::LookupFailed);
+    };
 
-    let included = tidal_album.included?;
-    let IncludedInner::Artists(artist) = included.first()? else {
-        return None;
+    let Some(album_attrs) = tidal_album.data.attributes.as_ref() else {
+        info!("no attrs on album");
+        return Err(MatchFailureReason::LookupFailed);
     };
 
     let query_string = format!(
         "album={}&upc={}&artist={}",
-        album_attrs.title,
-        album_attrs.barcode_id,
-        artist.attributes.clone()?.name
+        album_attrs.title, album_attrs.barcode_id, artist_name
     );
 
-    let rspotify::model::SearchResult::Albums(albums_search) = (match spotify_client
-        .search(
-            query_string.as_str(),
-            rspotify::model::SearchType::Album,
-            None,
-            None,
-            None,
-            None,
-        )
+    let rspotify::model::SearchResult::Albums(albums_search) =
+        (match ratelimit::call("spotify", "search_albums", None, || {
+            spotify_client.search(
+                query_string.as_str(),
+                rspotify::model::SearchType::Album,
+                None,
+                None,
+                None,
+                None,
+            )
+        })
         .await
-    {
-        Ok(s) => s,
-        Err(e) => {
-            error!("failed to search for spotify album: {e}");
-            return None;
-        }
-    }) else {
-        return None;
+        {
+            Ok(s) => s,
+            Err(e) => {
+                error!("failed to search for spotify album: {e}");
+                return Err(MatchFailureReason::LookupFailed);
+            }
+        })
+    else {
+        return Err(MatchFailureReason::LookupFailed);
     };
 
+    if albums_search.items.is_empty() {
+        return Err(MatchFailureReason::NoSearchResults);
+    }
+
     let mut full_albums = vec![];
 
     for simplified_album in albums_search.items {
         let Some(id) = simplified_album.id else {
             continue;
         };
-        let Ok(full_album) = spotify_client.album(id, None).await else {
+        let Ok(full_album) = ratelimit::call("spotify", "album", None, || {
+            spotify_client.album(id.clone(), None)
+        })
+        .await
+        else {
             continue;
         };
 
@@ -651,135 +897,217 @@ async fn match_spotify_album(
         .find(|t| -> bool { album_matches(album_attrs.as_ref(), t) })
     else {
         warn!("no album found");
-        return None;
+        return Err(MatchFailureReason::BelowThreshold);
     };
 
     let id = top_result.id.to_string().replace("spotify:album:", "");
     info!("matched album id: {id}");
 
-    Some(IdType::Album(id))
+    Ok(IdType::Album(id))
 }
 
+#[instrument(skip_all, fields(from = "tidal", to = "spotify", track_id = %tidal_track.data.id))]
 async fn match_spotify_track(
     spotify_client: &ClientCredsSpotify,
-    tidal_track: TracksSingleResourceDataDocument,
-) -> Option<IdType> {
-    let Some(track_attrs) = tidal_track.data.attributes else {
-        info!("no attrs on track");
-        return None;
+    tidal_track: &TracksSingleResourceDataDocument,
+) -> std::result::Result<IdType, MatchFailureReason> {
+    let Some(artist_name) = first_artist_name(tidal_track.included.as_ref()) else {
+        info!("no artist info");
+        return Err(MatchFailureReason::LookupFailed);
     };
 
-    let Some(included) = tidal_track.included else {
-        info!("no included data");
-        return None;
-    };
-    let IncludedInner::Artists(artist) = included.first()? else {
-        info!("no artist info");
-        return None;
+    let Some(track_attrs) = tidal_track.data.attributes.as_ref() else {
+        info!("no attrs on track");
+        return Err(MatchFailureReason::LookupFailed);
     };
 
     let query_string = format!(
         "album={}&isrc={}&artist={}",
-        track_attrs.title,
-        track_attrs.isrc,
-        artist.attributes.clone()?.name
+        track_attrs.title, track_attrs.isrc, artist_name
     );
 
-    let rspotify::model::SearchResult::Tracks(tracks_search) = (match spotify_client
-        .search(
-            query_string.as_str(),
-            rspotify::model::SearchType::Track,
-            None,
-            None,
-            None,
-            None,
-        )
+    let rspotify::model::SearchResult::Tracks(tracks_search) =
+        (match ratelimit::call("spotify", "search_tracks", None, || {
+            spotify_client.search(
+                query_string.as_str(),
+                rspotify::model::SearchType::Track,
+                None,
+                None,
+                None,
+                None,
+            )
+        })
         .await
-    {
-        Ok(s) => s,
-        Err(e) => {
-            error!("failed to search for spotify album: {e}");
-            return None;
-        }
-    }) else {
+        {
+            Ok(s) => s,
+            Err(e) => {
+                error!("failed to search for spotify album: {e}");
+                return Err(MatchFailureReason::LookupFailed);
+            }
+        })
+    else {
         info!("was not a track search  result");
-        return None;
+        return Err(MatchFailureReason::LookupFailed);
     };
 
+    if tracks_search.items.is_empty() {
+        return Err(MatchFailureReason::NoSearchResults);
+    }
+
     let Some(top_result) = tracks_search
         .items
         .iter()
         .find(|t| -> bool { track_matches(track_attrs.as_ref(), t) })
     else {
         warn!("no album found");
-        return None;
+        let title = normalize_track_name(track_attrs.title.as_str());
+        return Err(
+            if tracks_search
+                .items
+                .iter()
+                .any(|t| normalize_track_name(t.name.as_str()) == title)
+            {
+                MatchFailureReason::IsrcMismatch
+            } else {
+                MatchFailureReason::BelowThreshold
+            },
+        );
     };
 
     let Some(id) = top_result.id.clone() else {
         info!("no id on result");
-        return None;
+        return Err(MatchFailureReason::LookupFailed);
     };
 
     let id = id.to_string().replace("spotify:track:", "");
     info!("matched track id: {id}");
 
-    Some(IdType::Track(id))
+    Ok(IdType::Track(id))
 }
 
-async fn match_spotify_resources(
-    _: &TidalClient,
+async fn match_spotify_resource(
     spotify_client: &ClientCredsSpotify,
-    tidal_resources: Vec<FullTidalResource>,
-) -> Vec<IdType> {
-    let mut spotify_resource = vec![];
-    for resource in tidal_resources {
-        match resource {
-            FullTidalResource::Album(album) => {
-                let Some(matched_album) = match_spotify_album(spotify_client, album).await else {
-                    warn!("no album matched");
-                    continue;
-                };
+    resource: &FullTidalResource,
+) -> std::result::Result<IdType, MatchFailure> {
+    let matched = match resource {
+        FullTidalResource::Album(album) => match_spotify_album(spotify_client, album).await,
+        FullTidalResource::Track(track) => match_spotify_track(spotify_client, track).await,
+    };
 
-                spotify_resource.push(matched_album);
-            }
-            FullTidalResource::Track(track) => {
-                let Some(matched_track) = match_spotify_track(spotify_client, track).await else {
-                    warn!("no track matched");
-                    continue;
-                };
+    metrics::record_match("tidal", "spotify", matched.is_ok());
 
-                spotify_resource.push(matched_track);
-            }
+    matched.map_err(|reason| {
+        let description = describe(resource);
+        warn!("failed to match {description} on spotify: {reason}");
+        MatchFailure {
+            from: "tidal",
+            to: "spotify",
+            description,
+            reason,
         }
+    })
+}
+
+/// Matches each Tidal resource on Spotify with the automatic matcher, ignoring any
+/// `/fix_match` override. The results line up with `tidal_resources`.
+pub async fn match_spotify_resources(
+    spotify_client: &ClientCredsSpotify,
+    tidal_resources: &[FullTidalResource],
+) -> Vec<std::result::Result<IdType, MatchFailure>> {
+    let mut matches = vec![];
+
+    for resource in tidal_resources {
+        matches.push(match_spotify_resource(spotify_client, resource).await);
     }
 
-    spotify_resource
+    matches
 }
 
 pub async fn extract_resources(
+    conn: &Arc<Mutex<Connection>>,
     tidal_client: &TidalClient,
-    spotify_client: &ClientCredsSpotify,
+    spotify_client: Option<&ClientCredsSpotify>,
     msg: &str,
+    lookup: Lookup,
 ) -> Vec<ServiceResources> {
     if !contains_tidal_link(msg) {
         return vec![];
     }
 
-    let tidal_resources = extract_ids(msg);
+    let mut tidal_resources = extract_ids(msg);
+
+    metrics::record_links_extracted("tidal", tidal_resources.len());
+
+    let posted = ServiceResources::Posted(tidal_resources.iter().map(PostedLink::tidal).collect());
+
+    if !lookup.albums {
+        tidal_resources.retain(|r| matches!(r, TidalResource::Track(_)));
+    }
+
+    if tidal_resources.is_empty() {
+        return vec![posted];
+    }
 
     let full_tidal_resources =
         get_full_tidal_resources(tidal_client, tidal_resources.clone()).await;
 
-    let spotify_resources =
-        match_spotify_resources(tidal_client, spotify_client, full_tidal_resources).await;
+    let mut spotify_matches = vec![];
+
+    if let Some(spotify_client) = spotify_client {
+        for resource in &full_tidal_resources {
+            let matched = match matching::spotify_override(conn, &resource.resource()) {
+                Ok(Some(id)) => Ok(id),
+                Ok(None) => match_spotify_resource(spotify_client, resource).await,
+                Err(_) => Err(MatchFailure {
+                    from: "tidal",
+                    to: "spotify",
+                    description: describe(resource),
+                    reason: MatchFailureReason::LookupFailed,
+                }),
+            };
+
+            spotify_matches.push(matched);
+        }
+    }
+
+    let mut cards = vec![];
+
+    for (i, resource) in full_tidal_resources.iter().enumerate() {
+        let cover_url = if lookup.cover_art {
+            get_cover_art_url(tidal_client, resource).await
+        } else {
+            None
+        };
+        let spotify_match = spotify_matches.get(i).and_then(|m| m.as_ref().ok());
+
+        if let Some(card) = Card::from_tidal(resource, cover_url, spotify_match) {
+            cards.push(card);
+        }
+    }
+
+    let mut resources = vec![
+        posted,
+        ServiceResources::Tidal(tidal_resources),
+        ServiceResources::Cards(cards),
+    ];
+
+    let mut spotify_resources = vec![];
+    let mut failures = vec![];
+
+    for matched in spotify_matches {
+        match matched {
+            Ok(id) => spotify_resources.push(id),
+            Err(failure) => failures.push(failure),
+        }
+    }
 
     if !spotify_resources.is_empty() {
-        return [
-            ServiceResources::Tidal(tidal_resources),
-            ServiceResources::Spotify(spotify_resources),
-        ]
-        .to_vec();
+        resources.push(ServiceResources::Spotify(spotify_resources));
+    }
+
+    if !failures.is_empty() {
+        resources.push(ServiceResources::Unmatched(failures));
     }
 
-    [ServiceResources::Tidal(tidal_resources)].to_vec()
+    resources
 }
@@ -1,11 +1,14 @@
+use isopod::apis::Api;
 use isopod::client::{OAuthConfig, RetryConfig, Token, YoutubeClient, YoutubeClientConfig};
-use log::error;
+use isopod::models::Video;
 use regex::Regex;
-use std::env;
 use std::error::Error;
+use tracing::error;
 use url::Url;
 
-use crate::discord::ServiceResources;
+use crate::cards::Card;
+use crate::discord::{PostedLink, ServiceResources};
+use crate::{config, metrics, ratelimit};
 
 pub static DEFAULT_SCOPES: &[&str] = &["https://www.googleapis.com/auth/youtube"];
 
@@ -20,14 +23,13 @@ pub enum YoutubeResource {
 }
 
 pub fn get_redirect_uri() -> Result<String> {
-    let base_uri = env::var("BASE_REDIRECT_URI")?;
-
-    Ok(format!("{base_uri}/callback"))
+    Ok(config::get().server.callback_uri())
 }
 
 pub fn init_youtube() -> Result<YoutubeClient> {
-    let client_id = env::var("YOUTUBE_CLIENT_ID")?;
-    let client_secret = env::var("YOUTUBE_CLIENT_SECRET")?;
+    let youtube_config = config::youtube()?;
+    let client_id = youtube_config.client_id.clone();
+    let client_secret = youtube_config.client_secret.clone();
 
     let redirect_uri = get_redirect_uri()?;
 
@@ -45,8 +47,9 @@ pub fn init_youtube() -> Result<YoutubeClient> {
 }
 
 pub fn init_youtube_with_token(token: Token) -> Result<YoutubeClient> {
-    let client_id = env::var("TIDAL_CLIENT_ID")?;
-    let client_secret = env::var("YOUTUBE_CLIENT_SECRET")?;
+    let youtube_config = config::youtube()?;
+    let client_id = youtube_config.client_id.clone();
+    let client_secret = youtube_config.client_secret.clone();
 
     let redirect_uri = get_redirect_uri()?;
 
@@ -121,9 +124,85 @@ pub fn extract_ids(link: &str) -> Vec<YoutubeResource> {
         .collect()
 }
 
-pub fn extract_resources(link: &str) -> Vec<ServiceResources> {
+/// Looks up the details of `ids` with the configured Data API key. Returns nothing if
+/// there is no key, since the app has no token of its own to look them up with.
+pub async fn get_videos(ids: &[YoutubeResource]) -> Vec<Video> {
+    let Some(api_key) = config::youtube().ok().and_then(|c| c.api_key.as_deref()) else {
+        return vec![];
+    };
+
+    if ids.is_empty() {
+        return vec![];
+    }
+
+    let client = match init_youtube() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("error initializing youtube client: {e}");
+            return vec![];
+        }
+    };
+
+    let video_ids: Vec<String> = ids
+        .iter()
+        .map(|YoutubeResource::Video(id)| id.clone())
+        .collect();
+
+    match ratelimit::call("youtube", "videos_list", None, || {
+        client.videos_api().youtube_videos_list(
+            vec!["snippet".to_string()],
+            None,
+            None,
+            Some(video_ids.clone()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(api_key),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    })
+    .await
+    {
+        Ok(r) => r.items.unwrap_or_default(),
+        Err(e) => {
+            error!("error fetching youtube videos: {e}");
+            vec![]
+        }
+    }
+}
+
+pub async fn extract_resources(link: &str) -> Vec<ServiceResources> {
     if !contains_youtube_link(link) {
         return vec![];
     }
-    vec![ServiceResources::Youtube(extract_ids(link))]
+    let ids = extract_ids(link);
+
+    metrics::record_links_extracted("youtube", ids.len());
+
+    let cards = get_videos(&ids)
+        .await
+        .iter()
+        .filter_map(Card::from_youtube)
+        .collect();
+
+    vec![
+        ServiceResources::Posted(ids.iter().map(PostedLink::youtube).collect()),
+        ServiceResources::Youtube(ids),
+        ServiceResources::Cards(cards),
+    ]
 }